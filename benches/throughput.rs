@@ -0,0 +1,95 @@
+//! Throughput of replacing in bytes, for the iterator adapter and the byte
+//! specific paths, compared with the original `Replace`, which kept a set of
+//! candidate starts for each pattern.
+//!
+//! Run with `cargo bench`.
+
+extern crate iter_replace;
+
+mod baseline;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use iter_replace::{ReplaceBuilder, ReplaceIter, Replacement};
+
+// Counts allocations, to check that none are made per item
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const INPUT_LEN: usize = 8 * 1024 * 1024;
+
+// Words from a small vocabulary, so that patterns often partially match
+fn input() -> Vec<u8> {
+    let words: [&[u8]; 8] = [b"the", b"needle", b"in", b"a", b"haystack", b"needs", b"nee", b"neat"];
+    let mut seed = 1u32;
+    let mut input = Vec::with_capacity(INPUT_LEN + 16);
+    while input.len() < INPUT_LEN {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        input.extend_from_slice(words[(seed >> 16) as usize % words.len()]);
+        input.push(b' ');
+    }
+    input
+}
+
+fn measure<F: FnMut() -> Vec<u8>>(name: &str, mut f: F) -> Vec<u8> {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let out = f();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!("  {:<16} {:>8.1} MB/s {:>10} allocations",
+             name, INPUT_LEN as f64 / elapsed.as_secs_f64() / 1e6, allocations);
+    out
+}
+
+fn bench(name: &str, input: &[u8], patterns: &[(&[u8], &[u8])]) {
+    println!("{}:", name);
+    let reps = || patterns.iter().map(|&(s, r)| Replacement::new(s, r)).collect::<Vec<_>>();
+    let expected = measure("baseline", || {
+        let reps = patterns.iter().map(|&(s, r)| baseline::Replacement::new(s, r)).collect();
+        let mut out = Vec::with_capacity(2 * input.len());
+        out.extend(baseline::ReplaceIter::replace_all(input.iter().cloned(), reps));
+        out
+    });
+    let out = measure("iterator", || {
+        // into a Vec that is already big enough, so only the adapter allocates
+        let mut out = Vec::with_capacity(2 * input.len());
+        out.extend(input.iter().cloned().replace_all(reps()));
+        out
+    });
+    assert_eq!(out, expected);
+    let out = measure("replace_bytes", || ReplaceBuilder::new(reps()).replace_bytes(input));
+    assert_eq!(out, expected);
+    let out = measure("reader", || {
+        let mut out = Vec::with_capacity(2 * input.len());
+        ReplaceBuilder::new(reps()).reader(input).read_to_end(&mut out).unwrap();
+        out
+    });
+    assert_eq!(out, expected);
+}
+
+fn main() {
+    let input = input();
+    let boundary = [b'-'; 64];
+    bench("one word", &input, &[(b"needle", b"pin")]);
+    bench("several words", &input, &[(b"needle", b"pin"), (b"haystack", b"barn"), (b"the", b"a"), (b"neat", b"tidy")]);
+    bench("long pattern", &input, &[(&boundary, b"|")]);
+}
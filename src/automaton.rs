@@ -0,0 +1,272 @@
+//! An Aho-Corasick automaton over arbitrary item types.
+//!
+//! The patterns are compiled into a trie, and every trie node gets a failure
+//! link pointing at the node for its longest proper suffix that is also in the
+//! trie. Each item therefore follows an amortized constant number of links, no
+//! matter how many patterns there are.
+//!
+//! If items can only be compared for equality, finding a transition is linear
+//! in the number of children of a node. With an `Order`, the children are kept
+//! sorted and binary searched instead, and bytes get a table for the root.
+
+use std::cmp::Ordering;
+
+use MatchKind;
+use matcher::Ranking;
+
+pub type StateId = usize;
+
+/// The state corresponding to the empty prefix.
+pub const ROOT: StateId = 0;
+
+struct State<T> {
+    // transitions to child nodes, sorted by item if there is an order
+    trans: Vec<(T, StateId)>,
+    fail: StateId,
+    depth: usize,
+    // the patterns that end exactly at this node, best ranked first
+    patterns: Vec<usize>,
+    // the nearest node in the failure chain (including this one) that ends a pattern
+    output: Option<StateId>,
+    // the best rank of the patterns that end at any node strictly below this one
+    best_below: Option<usize>,
+}
+
+impl <T> State<T> {
+    fn new(depth: usize) -> State<T> {
+        State {
+            trans: Vec::new(),
+            fail: ROOT,
+            depth,
+            patterns: Vec::new(),
+            output: None,
+            best_below: None,
+        }
+    }
+}
+
+/// A total order of the items, consistent with their equality, which lets
+/// the transitions be sorted.
+pub struct Order<T> {
+    cmp: fn(&T, &T) -> Ordering,
+    // for bytes, the transitions from the root are looked up in a table
+    byte: Option<fn(&T) -> u8>,
+}
+
+impl <T> Clone for Order<T> {
+    fn clone(&self) -> Order<T> {
+        *self
+    }
+}
+
+impl <T> Copy for Order<T> {}
+
+impl <T: Ord> Order<T> {
+    pub fn ord() -> Order<T> {
+        Order { cmp: T::cmp, byte: None }
+    }
+}
+
+impl Order<u8> {
+    pub fn bytes() -> Order<u8> {
+        Order { cmp: u8::cmp, byte: Some(|&b| b) }
+    }
+}
+
+pub struct Automaton<T> {
+    states: Vec<State<T>>,
+    order: Option<Order<T>>,
+    // the state after each byte from the root, if the items are bytes
+    root_table: Vec<StateId>,
+    pub ranking: Ranking,
+}
+
+impl <T: PartialEq + Clone> Automaton<T> {
+
+    pub fn new<'a>(patterns: &[&'a [T]], ranking: Ranking, order: Option<Order<T>>) -> Automaton<T> where
+        T: 'a {
+
+        let mut states: Vec<State<T>> = vec![State::new(0)];
+
+        // In sorted order, a new child always goes after its siblings, and an
+        // existing one is the last of them
+        let mut sorted: Vec<usize> = (0 .. patterns.len()).collect();
+        if let Some(order) = order {
+            sorted.sort_by(|&a, &b| compare(order.cmp, patterns[a], patterns[b]));
+        }
+
+        for pattern_index in sorted {
+            let mut current = ROOT;
+            for item in patterns[pattern_index] {
+                let existing = match order {
+                    Some(_) => states[current].trans.last().filter(|(t, _)| t == item),
+                    None => states[current].trans.iter().find(|(t, _)| t == item),
+                };
+                current = match existing {
+                    Some(&(_, child)) => child,
+                    None => {
+                        let child = states.len();
+                        states.push(State::new(states[current].depth + 1));
+                        states[current].trans.push((item.clone(), child));
+                        child
+                    }
+                };
+            }
+            // identical patterns are shadowed by the highest ranked one, unless it is disabled
+            let patterns = &mut states[current].patterns;
+            let at = patterns.iter().position(|&p| ranking.rank(pattern_index) < ranking.rank(p));
+            patterns.insert(at.unwrap_or(patterns.len()), pattern_index);
+        }
+
+        let mut automaton = Automaton { states, order, root_table: Vec::new(), ranking };
+        if let Some(byte) = order.and_then(|order| order.byte) {
+            let mut table = vec![ROOT; 256];
+            for &(ref item, child) in &automaton.states[ROOT].trans {
+                table[byte(item) as usize] = child;
+            }
+            automaton.root_table = table;
+        }
+        automaton.fill_failure_links();
+        automaton.fill_best_below();
+        automaton
+    }
+
+    // Breadth-first, so that the failure target of every node is finalised before its children
+    fn fill_failure_links(&mut self) {
+        let mut queue = ::std::collections::VecDeque::new();
+        queue.push_back(ROOT);
+        while let Some(parent) = queue.pop_front() {
+            for i in 0 .. self.states[parent].trans.len() {
+                let (ref item, child) = self.states[parent].trans[i];
+                let fail = if parent == ROOT {
+                    ROOT
+                } else {
+                    self.next_state(self.states[parent].fail, item)
+                };
+                let output = if !self.states[child].patterns.is_empty() {
+                    Some(child)
+                } else {
+                    self.states[fail].output
+                };
+                self.states[child].fail = fail;
+                self.states[child].output = output;
+                queue.push_back(child);
+            }
+        }
+    }
+
+    // Children are always created after their parents, so visiting in reverse is post-order
+    fn fill_best_below(&mut self) {
+        for id in (0 .. self.states.len()).rev() {
+            let best = self.states[id].trans.iter()
+                .flat_map(|&(_, child)| {
+                    let child = &self.states[child];
+                    child.patterns.first().map(|&p| self.ranking.rank(p)).into_iter().chain(child.best_below)
+                })
+                .min();
+            self.states[id].best_below = best;
+        }
+    }
+
+    /// The state reached by consuming `item` from `state`.
+    #[inline]
+    pub fn next_state(&self, mut state: StateId, item: &T) -> StateId {
+        loop {
+            if state == ROOT {
+                if let Some(byte) = self.order.and_then(|order| order.byte) {
+                    return self.root_table[byte(item) as usize];
+                }
+            }
+            if let Some(child) = self.child(state, item) {
+                return child;
+            }
+            if state == ROOT {
+                return ROOT;
+            }
+            state = self.states[state].fail;
+        }
+    }
+
+    #[inline]
+    fn child(&self, state: StateId, item: &T) -> Option<StateId> {
+        let trans = &self.states[state].trans;
+        match self.order {
+            Some(order) => trans.binary_search_by(|(t, _)| (order.cmp)(t, item)).ok().map(|i| trans[i].1),
+            None => trans.iter().find(|(t, _)| t == item).map(|&(_, child)| child),
+        }
+    }
+
+    /// The first items of all of the patterns.
+    pub fn start_items(&self) -> impl Iterator<Item = &T> {
+        self.states[ROOT].trans.iter().map(|(item, _)| item)
+    }
+
+    /// The pattern, if there is only one. The trie is then a single chain.
+    pub fn only_pattern(&self) -> Option<Vec<T>> {
+        let mut pattern = Vec::new();
+        let mut state = &self.states[ROOT];
+        while state.patterns.is_empty() {
+            match state.trans[..] {
+                [(ref item, child)] => {
+                    pattern.push(item.clone());
+                    state = &self.states[child];
+                }
+                _ => return None,
+            }
+        }
+        if state.patterns.len() == 1 && state.trans.is_empty() {
+            Some(pattern)
+        } else {
+            None
+        }
+    }
+
+    /// The number of items since the start of the longest partial match.
+    #[inline]
+    pub fn depth(&self, state: StateId) -> usize {
+        self.states[state].depth
+    }
+
+    /// The best ranked enabled pattern of each length ending at this state,
+    /// longest first, as `(pattern_index, length)`, until `f` returns false.
+    pub fn each_match<E, F>(&self, state: StateId, enabled: E, mut f: F) where
+        E: Fn(usize) -> bool,
+        F: FnMut(usize, usize) -> bool {
+        let mut output = self.states[state].output;
+        while let Some(out) = output {
+            let out = &self.states[out];
+            if let Some(&pattern) = out.patterns.iter().find(|&&p| enabled(p)) {
+                if !f(pattern, out.depth) {
+                    return;
+                }
+            }
+            output = self.states[out.fail].output;
+        }
+    }
+
+    /// The state for the longest suffix of this one that is no longer than `max_depth`.
+    pub fn rebase(&self, mut state: StateId, max_depth: usize) -> StateId {
+        while self.states[state].depth > max_depth {
+            state = self.states[state].fail;
+        }
+        state
+    }
+
+    /// Whether continuing from this state could still complete a pattern
+    /// that would be preferred over `pattern`, starting at the same index.
+    pub fn can_improve(&self, state: StateId, pattern: usize) -> bool {
+        match self.ranking.kind {
+            // anything below this state is longer
+            MatchKind::LeftmostLongest => self.states[state].best_below.is_some(),
+            _ => self.states[state].best_below.is_some_and(|best| best < self.ranking.rank(pattern)),
+        }
+    }
+}
+
+// Compares patterns lexicographically
+fn compare<T>(cmp: fn(&T, &T) -> Ordering, a: &[T], b: &[T]) -> Ordering {
+    a.iter().zip(b)
+        .map(|(x, y)| cmp(x, y))
+        .find(|&o| o != Ordering::Equal)
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
@@ -0,0 +1,330 @@
+//! Streams bytes from stdin or files to stdout, or back to the files, with
+//! patterns replaced. Unlike `sed`, it isn't line-oriented, so patterns can
+//! contain newlines and any other bytes, and only the bytes of a partial
+//! match are held in memory.
+
+extern crate iter_replace;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use iter_replace::{BuildError, Bytes, MatchKind, ReplaceBuilder, Rule, RuleSet};
+
+const USAGE: &str = "\
+Usage: iter-replace [OPTIONS] [FILE]...
+
+Replaces patterns in the bytes of each FILE, or of stdin if there are none,
+and writes the result to stdout.
+
+Options:
+  -e, --replace SEARCH REPLACE  Replace SEARCH with REPLACE. Can be repeated.
+  -f, --rules FILE              Read rules from a .json, .toml, .yaml or .yml file.
+      --encoding ENCODING       How the arguments of -e are written: literal
+                                (the default), escaped (with \\n, \\xHH, ...) or hex.
+      --match-kind KIND         first (the default), longest or priority.
+  -n, --limit N                 Replace at most N matches in each input.
+  -i, --in-place                Write the output back to each FILE.
+  -h, --help                    Print this help.
+
+Where patterns overlap, the leftmost match wins, and then the one chosen by
+the match kind. With `first`, that is the rule given first.
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Literal,
+    Escaped,
+    Hex,
+}
+
+// A rule in the order it was given, with the arguments of -e not yet decoded
+enum RuleArg {
+    Pair(String, String),
+    Loaded(Rule<Bytes>),
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    rules: Vec<Rule<Bytes>>,
+    match_kind: Option<MatchKind>,
+    limit: Option<usize>,
+    in_place: bool,
+    files: Vec<PathBuf>,
+    help: bool,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("iter-replace: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        print!("{}", USAGE);
+        return;
+    }
+    if let Err(message) = check(&options) {
+        eprintln!("iter-replace: {}", message);
+        process::exit(2);
+    }
+    if let Err(message) = run(&options) {
+        eprintln!("iter-replace: {}", message);
+        process::exit(1);
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut encoding = Encoding::Literal;
+    // the -e arguments are decoded at the end, once the encoding is known
+    let mut rules = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match &arg[..] {
+            "-e" | "--replace" => {
+                let search = value(&arg)?;
+                let replace = value(&arg)?;
+                rules.push(RuleArg::Pair(search, replace));
+            }
+            "-f" | "--rules" => rules.extend(load_rules(Path::new(&value(&arg)?))?.into_iter().map(RuleArg::Loaded)),
+            "--encoding" => {
+                encoding = match &value(&arg)?[..] {
+                    "literal" => Encoding::Literal,
+                    "escaped" => Encoding::Escaped,
+                    "hex" => Encoding::Hex,
+                    other => return Err(format!("unknown encoding {:?}", other)),
+                };
+            }
+            "--match-kind" => {
+                options.match_kind = Some(match &value(&arg)?[..] {
+                    "first" => MatchKind::LeftmostFirst,
+                    "longest" => MatchKind::LeftmostLongest,
+                    "priority" => MatchKind::Priority,
+                    other => return Err(format!("unknown match kind {:?}", other)),
+                });
+            }
+            "-n" | "--limit" => {
+                let limit = value(&arg)?;
+                options.limit = Some(limit.parse().map_err(|_| format!("invalid limit {:?}", limit))?);
+            }
+            "-i" | "--in-place" => options.in_place = true,
+            "-h" | "--help" => options.help = true,
+            "--" => options.files.extend(args.by_ref().map(PathBuf::from)),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option {}", arg)),
+            _ => options.files.push(PathBuf::from(arg)),
+        }
+    }
+    for rule in rules {
+        options.rules.push(match rule {
+            RuleArg::Pair(search, replace) => Rule {
+                search: decode(&search, encoding)?,
+                replace: decode(&replace, encoding)?,
+                priority: None,
+                max_count: None,
+            },
+            RuleArg::Loaded(rule) => rule,
+        });
+    }
+    Ok(options)
+}
+
+fn decode(arg: &str, encoding: Encoding) -> Result<Bytes, String> {
+    let decoded = match encoding {
+        Encoding::Literal => Ok(Bytes(arg.as_bytes().to_vec())),
+        Encoding::Escaped => Bytes::escaped(arg),
+        Encoding::Hex => Bytes::hex(arg),
+    };
+    decoded.map_err(|e| format!("{:?}: {}", arg, e))
+}
+
+fn load_rules(path: &Path) -> Result<Vec<Rule<Bytes>>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let rules: Result<RuleSet<Bytes>, String> = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        _ => Err("rules files must be .json, .toml, .yaml or .yml".to_string()),
+    };
+    rules.map(|rules| rules.rules).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn builder(options: &Options) -> ReplaceBuilder<'static, u8> {
+    let mut builder = RuleSet { rules: options.rules.clone() }.into_builder();
+    if let Some(match_kind) = options.match_kind {
+        builder = builder.match_kind(match_kind);
+    }
+    if let Some(limit) = options.limit {
+        builder = builder.limit(limit);
+    }
+    builder
+}
+
+// Shadowed rules only get a warning, since the output is still well defined
+fn check(options: &Options) -> Result<(), String> {
+    if options.rules.is_empty() {
+        return Err("no rules were given".to_string());
+    }
+    if options.in_place && options.files.is_empty() {
+        return Err("--in-place needs at least one FILE".to_string());
+    }
+    for problem in builder(options).problems() {
+        match problem {
+            BuildError::ShadowedPattern { pattern_index, shadowed_by } => {
+                eprintln!("iter-replace: warning: rule {} can never match, because rule {} is always preferred",
+                          pattern_index + 1, shadowed_by + 1);
+            }
+            BuildError::EmptyPattern { pattern_index } => {
+                return Err(format!("rule {} has an empty pattern", pattern_index + 1));
+            }
+            error => return Err(error.to_string()),
+        }
+    }
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    if options.files.is_empty() {
+        let stdout = io::stdout();
+        let mut output = BufWriter::new(stdout.lock());
+        return replace(options, io::stdin().lock(), &mut output)
+            .and_then(|_| output.flush())
+            .map_err(|e| e.to_string());
+    }
+    if options.in_place {
+        for path in &options.files {
+            replace_in_place(options, path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        return Ok(());
+    }
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    for path in &options.files {
+        let result = if path == Path::new("-") {
+            replace(options, io::stdin().lock(), &mut output)
+        } else {
+            File::open(path).and_then(|file| replace(options, file, &mut output))
+        };
+        result.map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    output.flush().map_err(|e| e.to_string())
+}
+
+fn replace<R: Read, W: Write>(options: &Options, input: R, output: &mut W) -> io::Result<u64> {
+    io::copy(&mut builder(options).reader(input), output)
+}
+
+// Writes to a temporary file next to the original, and then renames it over the original
+fn replace_in_place(options: &Options, path: &Path) -> io::Result<()> {
+    let name = path.file_name().ok_or_else(|| io::Error::other("not a file"))?;
+    let mut temp_name = ".".to_string();
+    temp_name.push_str(&name.to_string_lossy());
+    temp_name.push_str(".iter-replace");
+    let temp_path = path.with_file_name(temp_name);
+    let result = File::open(path).and_then(|input| {
+        let mut output = BufWriter::new(File::create(&temp_path)?);
+        replace(options, input, &mut output)?;
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::set_permissions(&temp_path, fs::metadata(path)?.permissions())?;
+        fs::rename(&temp_path, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn replace_all(args: &[&str], input: &[u8]) -> Vec<u8> {
+        let options = parse(args).unwrap();
+        check(&options).unwrap();
+        let mut output = Vec::new();
+        replace(&options, input, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    pub fn test_parse_args(){
+        let options = parse(&["-e", "a", "b", "--match-kind", "longest", "-n", "3", "x.bin", "--", "-y"]).unwrap();
+        assert_eq!(options.rules.len(), 1);
+        assert_eq!(options.rules[0].search, Bytes(b"a".to_vec()));
+        assert_eq!(options.match_kind, Some(MatchKind::LeftmostLongest));
+        assert_eq!(options.limit, Some(3));
+        assert_eq!(options.files, vec![PathBuf::from("x.bin"), PathBuf::from("-y")]);
+        assert!(parse(&["-e", "a"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["-n", "many"]).is_err());
+    }
+
+    #[test]
+    pub fn test_encodings(){
+        assert_eq!(replace_all(&["-e", "\\n", "x"], b"a\\nb\n"), b"axb\n");
+        assert_eq!(replace_all(&["--encoding", "escaped", "-e", "\\n", "\\x00"], b"a\nb"), b"a\x00b");
+        assert_eq!(replace_all(&["-e", "0d 0a", "0a", "--encoding", "hex"], b"a\r\nb\r\n"), b"a\nb\n");
+        assert!(parse(&["--encoding", "hex", "-e", "0", "1"]).is_err());
+    }
+
+    #[test]
+    pub fn test_match_kind_and_limit(){
+        assert_eq!(replace_all(&["-e", "a", "1", "-e", "ab", "2"], b"abab"), b"1b1b");
+        assert_eq!(replace_all(&["-e", "a", "1", "-e", "ab", "2", "--match-kind", "longest"], b"abab"), b"22");
+        assert_eq!(replace_all(&["-e", "a", "1", "-n", "1"], b"aaa"), b"1aa");
+    }
+
+    #[test]
+    pub fn test_check(){
+        assert!(check(&parse(&[]).unwrap()).is_err());
+        assert!(check(&parse(&["-e", "", "x"]).unwrap()).is_err());
+        assert!(check(&parse(&["-i", "-e", "a", "b"]).unwrap()).is_err());
+        // only a warning
+        assert!(check(&parse(&["-e", "a", "1", "-e", "a", "2"]).unwrap()).is_ok());
+    }
+
+    #[test]
+    pub fn test_rules_file_and_in_place(){
+        let dir = env::temp_dir().join(format!("iter-replace-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rules = dir.join("rules.toml");
+        fs::write(&rules, "[[rules]]\nsearch = { hex = \"ff\" }\nreplace = \"\\\\0\"\nmax_count = 1\n").unwrap();
+        let data = dir.join("data.bin");
+        fs::write(&data, b"\xff\xfe\xff").unwrap();
+
+        let options = parse(&["-f", rules.to_str().unwrap(), "-i", data.to_str().unwrap()]).unwrap();
+        check(&options).unwrap();
+        run(&options).unwrap();
+        assert_eq!(fs::read(&data).unwrap(), b"\x00\xfe\xff");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_rules_keep_command_line_order(){
+        let dir = env::temp_dir().join(format!("iter-replace-order-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rules = dir.join("rules.json");
+        fs::write(&rules, r#"{"rules": [{"search": "ab", "replace": "f"}]}"#).unwrap();
+        let rules = rules.to_str().unwrap();
+
+        let options = parse(&["-e", "a", "1", "-f", rules, "-e", "ab", "2"]).unwrap();
+        let searches: Vec<_> = options.rules.iter().map(|rule| &rule.search.0[..]).collect();
+        assert_eq!(searches, vec![&b"a"[..], b"ab", b"ab"]);
+        assert_eq!(replace_all(&["-e", "ab", "e", "-f", rules], b"abab"), b"ee");
+        assert_eq!(replace_all(&["-f", rules, "-e", "ab", "e"], b"abab"), b"ff");
+        assert_eq!(replace_all(&["-e", "61", "78", "-f", rules, "--encoding", "hex"], b"ab"), b"xb");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
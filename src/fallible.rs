@@ -0,0 +1,322 @@
+//! Replacement in iterators of `Result`s, such as the bytes of an `io::Read`.
+//!
+//! Only the `Ok` values are matched. An error ends the current stretch of
+//! values as if it were the end of the stream, so no match spans an error.
+//!
+//! `CheckedReplace` is the other way around: its source can't fail, but it
+//! yields the `BufferOverflow` of a `CheckedBuilder`.
+
+use std::collections::VecDeque;
+use std::iter::{Fuse, FusedIterator};
+use std::sync::Arc;
+
+use {BufferOverflow, BufferPolicy, BuildError, CheckedBuilder, CheckedReplacer, ReplaceBuilder, Replacement, Replacer, ReplaceSink,
+     Sharing, Substitutes};
+use searcher::Searcher;
+
+/// What a `TryReplace` does after passing on an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnError {
+    /// Keep going with the values after the error.
+    #[default]
+    Continue,
+    /// End after the error, without pulling anything else from the source.
+    Stop,
+}
+
+/// An iterator adapter that replaces patterns in the `Ok` values of another
+/// iterator, passing errors through in the same position.
+pub struct TryReplace <'a, I, T: 'a + Clone, E> {
+    iter: Fuse<I>,
+    buffer_out: VecDeque<T>,
+    // an error that is due once buffer_out has been emptied
+    error: Option<E>,
+    searcher: Searcher<'a, T>,
+    replace_with: Arc<dyn Substitutes<T> + 'a>,
+    on_error: OnError,
+    stopped: bool,
+}
+
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    /// Like `apply`, but for an iterator of `Result`s.
+    pub fn try_apply<I, E>(self, iter: I, on_error: OnError) -> TryReplace<'a, I::IntoIter, T, E> where
+        I: IntoIterator<Item = Result<T, E>> {
+        self.compile().try_apply(iter, on_error)
+    }
+
+    /// Caps the number of held back items like `max_buffer`, but fails with a
+    /// `BufferOverflow` once a partial match needs more. Only the adapters that
+    /// can report the error are available after this, so it goes last.
+    pub fn checked(mut self, max_buffer: usize) -> CheckedBuilder<'a, T, S> {
+        self.max_buffer = Some((max_buffer, BufferPolicy::Fail));
+        CheckedBuilder { builder: self }
+    }
+}
+
+impl <'a, T, S> Replacer <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    pub fn try_apply<I, E>(&self, iter: I, on_error: OnError) -> TryReplace<'a, I::IntoIter, T, E> where
+        I: IntoIterator<Item = Result<T, E>> {
+        let (searcher, replace_with) = self.searcher();
+        TryReplace {
+            iter: iter.into_iter().fuse(),
+            buffer_out: VecDeque::new(),
+            error: None,
+            searcher,
+            replace_with,
+            on_error,
+            stopped: false,
+        }
+    }
+
+}
+
+impl <'a, T, S> CheckedBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    /// See `ReplaceBuilder::build`.
+    pub fn build(self) -> Result<CheckedReplacer<'a, T, S>, BuildError> {
+        self.builder.build().map(|replacer| CheckedReplacer { replacer })
+    }
+
+    /// See `ReplaceBuilder::problems`.
+    pub fn problems(&self) -> Vec<BuildError> {
+        self.builder.problems()
+    }
+
+    /// Like `ReplaceBuilder::apply`, but yields the `BufferOverflow` as an error.
+    pub fn apply<I>(self, iter: I) -> CheckedReplace<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        CheckedReplacer { replacer: self.builder.compile() }.apply(iter)
+    }
+}
+
+impl <'a, T, S> CheckedReplacer <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    pub fn apply<I>(&self, iter: I) -> CheckedReplace<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        let (searcher, replace_with) = self.replacer.searcher();
+        CheckedReplace {
+            iter: iter.into_iter().fuse(),
+            buffer_out: VecDeque::new(),
+            searcher,
+            replace_with,
+            failed: false,
+        }
+    }
+}
+
+impl <'a, I, T, E> TryReplace <'a, I, T, E> where
+    I: Iterator<Item = Result<T, E>>,
+    T: PartialEq + Clone {
+
+    fn fill_buffer(&mut self) {
+        let mut sink = ReplaceSink {
+            replace_with: &*self.replace_with,
+            buffer_out: &mut self.buffer_out,
+        };
+        while sink.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+            match self.iter.next() {
+                Some(Ok(item)) => {
+                    if self.searcher.try_push(item, &mut sink).is_err() {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    // Everything before the error goes first
+                    self.searcher.finish(&mut sink);
+                    self.searcher.resume();
+                    self.error = Some(e);
+                    break;
+                }
+                None => {
+                    self.searcher.finish(&mut sink);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn error(&mut self, e: E) -> Option<Result<T, E>> {
+        self.stopped = self.on_error == OnError::Stop;
+        Some(Err(e))
+    }
+}
+
+impl <'a, I, T, E> Iterator for TryReplace <'a, I, T, E> where
+    I: Iterator<Item = Result<T, E>>,
+    T: PartialEq + Clone {
+
+    type Item = Result<T, E>;
+
+    fn next(&mut self) -> Option<Result<T, E>> {
+        if self.buffer_out.is_empty() && self.error.is_none() && !self.stopped && !self.searcher.is_exhausted() {
+            self.fill_buffer();
+        }
+        if let Some(item) = self.buffer_out.pop_front() {
+            return Some(Ok(item));
+        }
+        if let Some(e) = self.error.take() {
+            return self.error(e);
+        }
+        if self.stopped || !self.searcher.is_exhausted() {
+            return None;
+        }
+        match self.iter.next() {
+            Some(Err(e)) => self.error(e),
+            item => item,
+        }
+    }
+}
+
+impl <'a, I, T, E> FusedIterator for TryReplace <'a, I, T, E> where
+    I: Iterator<Item = Result<T, E>>,
+    T: PartialEq + Clone {}
+
+/// An iterator adapter like `Replace` that can fail. Once more than
+/// `max_buffer` items would have to be held back, it yields the items before
+/// them and then the `BufferOverflow`, and ends.
+pub struct CheckedReplace <'a, I, T: 'a + Clone> {
+    iter: Fuse<I>,
+    buffer_out: VecDeque<T>,
+    searcher: Searcher<'a, T>,
+    replace_with: Arc<dyn Substitutes<T> + 'a>,
+    // whether the overflow has been yielded
+    failed: bool,
+}
+
+impl <'a, I, T> Iterator for CheckedReplace <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {
+
+    type Item = Result<T, BufferOverflow>;
+
+    fn next(&mut self) -> Option<Result<T, BufferOverflow>> {
+        if self.buffer_out.is_empty() && self.searcher.overflow().is_none() {
+            let mut sink = ReplaceSink {
+                replace_with: &*self.replace_with,
+                buffer_out: &mut self.buffer_out,
+            };
+            while sink.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+                match self.iter.next() {
+                    Some(item) => {
+                        if self.searcher.try_push(item, &mut sink).is_err() {
+                            break;
+                        }
+                    }
+                    None => {
+                        self.searcher.finish(&mut sink);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(item) = self.buffer_out.pop_front() {
+            return Some(Ok(item));
+        }
+        match self.searcher.overflow() {
+            Some(_) if self.failed => None,
+            Some(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+            None if self.searcher.is_exhausted() => self.iter.next().map(Ok),
+            None => None,
+        }
+    }
+}
+
+impl <'a, I, T> FusedIterator for CheckedReplace <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {}
+
+pub trait TryReplaceIter<'a, I, T, E> where
+    I: Iterator<Item = Result<T, E>>,
+    T: Clone {
+
+    fn try_replace_all(self, replacements: Vec<Replacement<'a, T>>, on_error: OnError) -> TryReplace<'a, I, T, E>;
+}
+
+impl <'a, I, T, E> TryReplaceIter<'a, I, T, E> for I where
+    I: Iterator<Item = Result<T, E>>,
+    T: PartialEq + Clone {
+
+    fn try_replace_all(self, replacements: Vec<Replacement<'a, T>>, on_error: OnError) -> TryReplace<'a, I, T, E> {
+        ReplaceBuilder::new(replacements).try_apply(self, on_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Read};
+
+    #[test]
+    pub fn test_try_replace_all(){
+        let reps = vec![Replacement::new(b"abc", b"X"), Replacement::new(b"ab", b"Y")];
+        let input: [Result<u8, &str>; 6] = [Ok(b'x'), Ok(b'a'), Ok(b'b'), Ok(b'c'), Ok(b'a'), Ok(b'b')];
+        let v: Vec<_> = input.iter().cloned()
+            .try_replace_all(reps, OnError::Continue)
+            .collect();
+        assert_eq!(v, vec![Ok(b'x'), Ok(b'X'), Ok(b'Y')]);
+    }
+
+    #[test]
+    pub fn test_error_ends_matches_in_order(){
+        let reps = || vec![Replacement::new(b"abc", b"X"), Replacement::new(b"ab", b"Y")];
+        let input = [Ok(b'a'), Ok(b'b'), Err("first"), Ok(b'c'), Ok(b'a'), Err("second"), Ok(b'b'), Ok(b'c')];
+        let v: Vec<_> = input.iter().cloned().try_replace_all(reps(), OnError::Continue).collect();
+        assert_eq!(v, vec![Ok(b'Y'), Err("first"), Ok(b'c'), Ok(b'a'), Err("second"), Ok(b'b'), Ok(b'c')]);
+
+        let v: Vec<_> = input.iter().cloned().try_replace_all(reps(), OnError::Stop).collect();
+        assert_eq!(v, vec![Ok(b'Y'), Err("first")]);
+    }
+
+    #[test]
+    pub fn test_error_keeps_insertions_on_both_sides(){
+        let reps = vec![Replacement::new(b"", b"-"), Replacement::new(b"a", b"A")];
+        let input = [Ok(b'a'), Err("error"), Ok(b'b')];
+        let v: Vec<_> = ReplaceBuilder::new(reps).allow_empty_patterns()
+            .try_apply(input.iter().cloned(), OnError::Continue)
+            .collect();
+        assert_eq!(v, vec![Ok(b'A'), Ok(b'-'), Err("error"), Ok(b'-'), Ok(b'b'), Ok(b'-')]);
+    }
+
+    #[test]
+    pub fn test_error_after_limit(){
+        let reps = vec![Replacement::new(b"abc", b"X"), Replacement::new(b"ab", b"Y")];
+        let input = [Ok(b'a'), Ok(b'b'), Ok(b'a'), Err("error"), Ok(b'a'), Ok(b'b')];
+        let v: Vec<_> = ReplaceBuilder::new(reps).limit(1)
+            .try_apply(input.iter().cloned(), OnError::Stop)
+            .collect();
+        assert_eq!(v, vec![Ok(b'Y'), Ok(b'a'), Err("error")]);
+    }
+
+    #[test]
+    pub fn test_checked_builder(){
+        let builder = || ReplaceBuilder::new(vec![Replacement::new(b"abcd", b"X")]).checked(2);
+        let v: Vec<_> = builder().apply(b"xabyab".iter().cloned()).collect();
+        assert_eq!(v, vec![Ok(b'x'), Ok(b'a'), Ok(b'b'), Ok(b'y'), Ok(b'a'), Ok(b'b')]);
+        let replacer = builder().build().unwrap();
+        let v: Vec<_> = replacer.apply(b"xyabcd".iter().cloned()).collect();
+        assert_eq!(v, vec![Ok(b'x'), Ok(b'y'), Err(BufferOverflow { max_buffer: 2 })]);
+        let v: Vec<_> = replacer.apply(b"abcd".iter().cloned()).collect();
+        assert_eq!(v, vec![Err(BufferOverflow { max_buffer: 2 })]);
+    }
+
+    #[test]
+    pub fn test_try_replace_io_bytes(){
+        let reps = vec![Replacement::new(b"abc", b"X"), Replacement::new(b"ab", b"Y")];
+        let bytes = io::Cursor::new(b"a cabbage".to_vec()).bytes();
+        let v: io::Result<Vec<u8>> = bytes.try_replace_all(reps, OnError::Stop).collect();
+        assert_eq!(v.unwrap().as_slice(), b"a cYbage");
+    }
+}
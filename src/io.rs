@@ -0,0 +1,309 @@
+//! Adapters that replace bytes in `std::io` streams.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use {CheckedBuilder, CheckedReplacer, ReplaceBuilder, Replacement, Replacer, ReplaceSink, Sharing, Substitutes};
+use prefilter::byte_searcher;
+use searcher::Searcher;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// A reader that replaces patterns in the bytes read from another reader.
+///
+/// Bytes that might be the start of a match are held back until the match is
+/// complete or fails, even when it spans several reads of the inner reader.
+pub struct ReplaceReader <'a, R> {
+    inner: R,
+    searcher: Searcher<'a, u8>,
+    replace_with: Arc<dyn Substitutes<u8> + 'a>,
+    buffer_out: VecDeque<u8>,
+    chunk: Vec<u8>,
+    done: bool,
+}
+
+impl <'a, R: Read> ReplaceReader <'a, R> {
+    pub fn new(inner: R, replacements: Vec<Replacement<'a, u8>>) -> ReplaceReader<'a, R> {
+        ReplaceBuilder::new(replacements).reader(inner)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> ReplaceBuilder <'a, u8, S> {
+    /// Replaces the patterns in the bytes read from `inner`.
+    pub fn reader<R: Read>(self, inner: R) -> ReplaceReader<'a, R> {
+        self.compile_bytes().reader(inner)
+    }
+
+    /// Replaces the patterns in the bytes written to `inner`.
+    pub fn writer<W: Write>(self, inner: W) -> ReplaceWriter<'a, W> {
+        self.compile_bytes().writer(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> CheckedBuilder <'a, u8, S> {
+    /// Like `ReplaceBuilder::reader`, but a `BufferOverflow` is returned as an
+    /// `io::Error` of kind `Other`.
+    pub fn reader<R: Read>(self, inner: R) -> ReplaceReader<'a, R> {
+        self.builder.reader(inner)
+    }
+
+    /// Like `ReplaceBuilder::writer`, but a `BufferOverflow` is returned as an
+    /// `io::Error` of kind `Other`.
+    pub fn writer<W: Write>(self, inner: W) -> ReplaceWriter<'a, W> {
+        self.builder.writer(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> CheckedReplacer <'a, u8, S> {
+    pub fn reader<R: Read>(&self, inner: R) -> ReplaceReader<'a, R> {
+        self.replacer.reader(inner)
+    }
+
+    pub fn writer<W: Write>(&self, inner: W) -> ReplaceWriter<'a, W> {
+        self.replacer.writer(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> Replacer <'a, u8, S> {
+    pub fn reader<R: Read>(&self, inner: R) -> ReplaceReader<'a, R> {
+        let (searcher, replace_with) = byte_searcher(self);
+        ReplaceReader {
+            inner,
+            searcher,
+            replace_with,
+            buffer_out: VecDeque::new(),
+            chunk: vec![0; CHUNK_SIZE],
+            done: false,
+        }
+    }
+}
+
+impl <'a, R: Read> Read for ReplaceReader <'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = self.searcher.overflow() {
+            return Err(io::Error::other(e));
+        }
+        let mut sink = ReplaceSink {
+            replace_with: &*self.replace_with,
+            buffer_out: &mut self.buffer_out,
+        };
+        while sink.buffer_out.is_empty() && !self.done && !self.searcher.is_exhausted() {
+            let n = match self.inner.read(&mut self.chunk) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                self.searcher.finish(&mut sink);
+                self.done = true;
+            }
+            self.searcher.try_push_slice(&self.chunk[.. n], &mut sink).map_err(io::Error::other)?;
+        }
+        if self.buffer_out.is_empty() && self.searcher.is_exhausted() && !self.done {
+            return self.inner.read(buf);
+        }
+        self.buffer_out.read(buf)
+    }
+}
+
+/// A writer that replaces patterns in the bytes written to it before passing
+/// them on to another writer.
+///
+/// Bytes that might be the start of a match are held back until the match is
+/// complete or fails, even across calls to `write` and `flush`. They are only
+/// written once `finish` is called, or when the writer is dropped.
+pub struct ReplaceWriter <'a, W: Write> {
+    // only None once finished
+    inner: Option<W>,
+    searcher: Searcher<'a, u8>,
+    replace_with: Arc<dyn Substitutes<u8> + 'a>,
+    buffer_out: VecDeque<u8>,
+}
+
+impl <'a, W: Write> ReplaceWriter <'a, W> {
+    pub fn new(inner: W, replacements: Vec<Replacement<'a, u8>>) -> ReplaceWriter<'a, W> {
+        ReplaceBuilder::new(replacements).writer(inner)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Ends the stream, so that any partial matches are written as they are,
+    /// and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        if let Some(e) = self.searcher.overflow() {
+            return Err(io::Error::other(e));
+        }
+        self.searcher.finish(&mut ReplaceSink {
+            replace_with: &*self.replace_with,
+            buffer_out: &mut self.buffer_out,
+        });
+        self.write_out()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+
+    // Writes everything that has been replaced so far
+    fn write_out(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().unwrap();
+        while !self.buffer_out.is_empty() {
+            let written = match inner.write(self.buffer_out.as_slices().0) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write the replaced data")),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.buffer_out.drain(.. written);
+        }
+        Ok(())
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> Replacer <'a, u8, S> {
+    pub fn writer<W: Write>(&self, inner: W) -> ReplaceWriter<'a, W> {
+        let (searcher, replace_with) = byte_searcher(self);
+        ReplaceWriter {
+            inner: Some(inner),
+            searcher,
+            replace_with,
+            buffer_out: VecDeque::new(),
+        }
+    }
+}
+
+impl <'a, W: Write> Write for ReplaceWriter <'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The output of earlier writes goes first, so that nothing is accepted if it fails
+        self.write_out()?;
+        if self.searcher.is_exhausted() {
+            return self.inner.as_mut().unwrap().write(buf);
+        }
+        let mut sink = ReplaceSink {
+            replace_with: &*self.replace_with,
+            buffer_out: &mut self.buffer_out,
+        };
+        self.searcher.try_push_slice(buf, &mut sink).map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    /// Writes and flushes everything except the bytes of a partial match.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_out()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl <'a, W: Write> Drop for ReplaceWriter <'a, W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            // like BufWriter, errors can't be reported here
+            let _ = self.end();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {BufferOverflow, ReplaceIter};
+
+    // Reads at most a few bytes at a time, so that matches span several reads
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl <'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.step = self.step % 3 + 1;
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[.. n].copy_from_slice(&self.data[.. n]);
+            self.data = &self.data[n ..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    pub fn test_reader_across_chunks() {
+        let reps = || vec![Replacement::new(b"needle", b"NEEDLE"),
+                             Replacement::new(b"need", b"?"),
+                             Replacement::new(b"dl", b"-")];
+        let input = b"a needle, needs a needl and a needle";
+        let expected: Vec<u8> = input.iter().cloned().replace_all(reps()).collect();
+
+        let mut output = Vec::new();
+        ReplaceReader::new(Trickle { data: input, step: 0 }, reps())
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(output.as_slice(), &b"a NEEDLE, ?s a ?l and a NEEDLE"[..]);
+    }
+
+    #[test]
+    pub fn test_reader_max_buffer(){
+        let reps = vec![Replacement::new(b"abcdef", b"1")];
+        let mut reader = ReplaceBuilder::new(reps)
+            .checked(3)
+            .reader(Trickle { data: b"abcxabcdx", step: 0 });
+        let mut output = Vec::new();
+        let err = reader.read_to_end(&mut output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.into_inner().unwrap().downcast_ref(), Some(&BufferOverflow { max_buffer: 3 }));
+        assert!(reader.read(&mut [0; 4]).is_err());
+    }
+
+    #[test]
+    pub fn test_reader_with_limit() {
+        let reps = vec![Replacement::new(b"needle", b"NEEDLE")];
+        let input = b"needle needle needle";
+        let mut output = Vec::new();
+        ReplaceBuilder::new(reps)
+            .limit(1)
+            .reader(Trickle { data: input, step: 0 })
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output.as_slice(), &b"NEEDLE needle needle"[..]);
+    }
+
+    #[test]
+    pub fn test_writer_across_writes() {
+        let reps = vec![Replacement::new(b"needle", b"NEEDLE"),
+                        Replacement::new(b"need", b"?"),
+                        Replacement::new(b"dl", b"-")];
+        let input = b"a needle, needs a needl and a need";
+        let mut writer = ReplaceWriter::new(Vec::new(), reps);
+        for chunk in input.chunks(4) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.flush().unwrap();
+        // the final "need" might still be the start of "needle"
+        assert_eq!(writer.get_ref().as_slice(), &b"a NEEDLE, ?s a ?l and a "[..]);
+        let output = writer.finish().unwrap();
+        assert_eq!(output.as_slice(), &b"a NEEDLE, ?s a ?l and a ?"[..]);
+    }
+
+    #[test]
+    pub fn test_writer_finishes_on_drop() {
+        let reps = vec![Replacement::new(b"needle", b"NEEDLE")];
+        let mut output = Vec::new();
+        {
+            let mut writer = ReplaceWriter::new(&mut output, reps);
+            writer.write_all(b"a nee").unwrap();
+        }
+        assert_eq!(output.as_slice(), &b"a nee"[..]);
+    }
+}
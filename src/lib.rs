@@ -1,187 +1,812 @@
+extern crate memchr;
+#[cfg(feature = "stream")]
+extern crate futures_core;
+#[cfg(feature = "stream")]
+extern crate tokio;
+#[cfg(all(test, feature = "stream"))]
+extern crate futures;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_yaml;
+#[cfg(all(test, feature = "serde"))]
+extern crate toml;
 
-use std::collections::{BTreeSet, BTreeMap, VecDeque};
-use std::cell::RefCell;
+mod automaton;
+mod fallible;
+mod io;
+mod matcher;
+mod nfa;
+mod prefilter;
+#[cfg(feature = "serde")]
+mod rules;
+mod searcher;
+mod slice;
+#[cfg(feature = "stream")]
+mod stream;
+mod text;
+mod validate;
 
-///
-pub struct Replace <'a, I, T: 'a + Ord > {
-    iter: I,
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::iter::{Fuse, FusedIterator};
+use std::sync::Arc;
+use automaton::Order;
+use matcher::{Equality, Matcher, Pattern, Ranking};
+use searcher::{Searcher, Sink};
+
+pub use fallible::{CheckedReplace, OnError, TryReplace, TryReplaceIter};
+pub use io::{ReplaceReader, ReplaceWriter};
+pub use slice::{replace_in_slice, replace_in_slice_cow, ReplaceVec};
+pub use text::{replace_all_chars, replace_all_str, ReplaceChars};
+pub use validate::BuildError;
+#[cfg(feature = "serde")]
+pub use rules::{Bytes, ParseBytesError, Rule, RuleSet};
+#[cfg(feature = "stream")]
+pub use stream::{ReplaceAsyncRead, ReplaceAsyncReadExt, ReplaceStream, ReplaceStreamExt};
+
+/// An iterator adapter that replaces occurrences of patterns with other items.
+pub struct Replace <'a, I, T: 'a + Clone> {
+    iter: Fuse<I>,
     buffer_out: VecDeque<T>,
-    buffer_in: Vec<T>,
-    replace_states: Vec<ReplaceState<'a, T>>,
-    index: usize,
-    flushed_index: usize,
+    searcher: Searcher<'a, T>,
+    replace_with: Arc<dyn Substitutes<T> + 'a>,
+}
+
+/// A pattern to search for and the items to replace it with.
+///
+/// The items can either be borrowed, or owned so that the `Replace` iterator
+/// doesn't borrow anything, e.g. when the patterns are built at runtime.
+///
+/// `S` is `Shared` for the replacements of `ReplaceBuilder::shared`.
+pub struct Replacement <'a, T: 'a + Clone, S: Sharing<'a, T> = Local> {
+    search_for: Pattern<'a, T>,
+    replace_with: Substitute<'a, T, S>,
+    priority: u32,
+    limit: Option<usize>,
+}
+
+/// How the functions of a set of replacements are boxed, which decides
+/// whether its `Replacer` can be shared between threads.
+pub trait Sharing<'a, T>: 'static {
+    /// Writes the substitution for a match, given the matched items and the
+    /// number of previous matches.
+    type SubstituteFn: ?Sized + Fn(&[T], usize, &mut VecDeque<T>) + 'a;
+    /// Compares an item from a pattern with an item being matched.
+    type EqFn: ?Sized + Fn(&T, &T) -> bool + 'a;
+}
+
+/// The default for the replacements, builders and `Replacer`s whose functions
+/// can only be used on one thread.
+#[derive(Clone, Copy, Debug)]
+pub enum Local {}
+
+impl <'a, T: 'a> Sharing<'a, T> for Local {
+    type SubstituteFn = dyn Fn(&[T], usize, &mut VecDeque<T>) + 'a;
+    type EqFn = dyn Fn(&T, &T) -> bool + 'a;
+}
+
+/// Marks the replacements, builders and `Replacer`s whose functions are all
+/// `Send + Sync`, so that the `Replacer` is `Send` and `Sync` if the items are.
+#[derive(Clone, Copy, Debug)]
+pub enum Shared {}
+
+impl <'a, T: 'a> Sharing<'a, T> for Shared {
+    type SubstituteFn = dyn Fn(&[T], usize, &mut VecDeque<T>) + Send + Sync + 'a;
+    type EqFn = dyn Fn(&T, &T) -> bool + Send + Sync + 'a;
+}
+
+/// An element of a pattern, which matches a single item.
+#[derive(Clone, Debug)]
+pub enum Element<T> {
+    /// Matches an item equal to this one.
+    Exact(T),
+    /// Matches any item.
+    Any,
+    /// Matches any of these items.
+    OneOf(Vec<T>),
+    /// Matches any item except these.
+    NoneOf(Vec<T>),
+    /// Matches the items for which the function returns true.
+    Pred(fn(&T) -> bool),
+}
+
+impl <T> Element<T> {
+    pub fn matches(&self, item: &T) -> bool where T: PartialEq {
+        self.matches_by(item, T::eq)
+    }
+
+    /// Matches using a custom equality, which is called with an item from the
+    /// pattern and then the item being matched.
+    pub fn matches_by<F>(&self, item: &T, eq: F) -> bool where
+        F: Fn(&T, &T) -> bool {
+        match *self {
+            Element::Exact(ref t) => eq(t, item),
+            Element::Any => true,
+            Element::OneOf(ref set) => set.iter().any(|t| eq(t, item)),
+            Element::NoneOf(ref set) => !set.iter().any(|t| eq(t, item)),
+            Element::Pred(f) => f(item),
+        }
+    }
 }
 
-pub struct Replacement <'a, T: 'a + Ord> {
-    search_for: &'a [T],
-    replace_with: &'a [T],
+// What a match is replaced with
+enum Substitute<'a, T: 'a + Clone, S: Sharing<'a, T>> {
+    Items(Cow<'a, [T]>),
+    Fn(Box<S::SubstituteFn>),
 }
 
-impl <'a, T: 'a + Ord> Replacement <'a, T> {
-    pub fn new(search_for: &'a [T], replace_with: &'a [T]) -> Replacement<'a, T> {
+// The substitutions for each pattern, whichever way their functions are boxed
+trait Substitutes<T> {
+    fn write(&self, pattern: usize, matched: &[T], match_index: usize, out: &mut VecDeque<T>);
+}
+
+impl <'a, T: Clone, S: Sharing<'a, T>> Substitutes<T> for Vec<Substitute<'a, T, S>> {
+    fn write(&self, pattern: usize, matched: &[T], match_index: usize, out: &mut VecDeque<T>) {
+        match self[pattern] {
+            Substitute::Items(ref items) => out.extend(items.iter().cloned()),
+            Substitute::Fn(ref f) => f(matched, match_index, out),
+        }
+    }
+}
+
+impl <'a, T: 'a + Clone, S: Sharing<'a, T>> Replacement <'a, T, S> {
+    pub fn new(search_for: &'a [T], replace_with: &'a [T]) -> Replacement<'a, T, S> {
+        Replacement {
+            search_for: Pattern::Literal(Cow::Borrowed(search_for)),
+            replace_with: Substitute::Items(Cow::Borrowed(replace_with)),
+            priority: 0,
+            limit: None,
+        }
+    }
+
+    pub fn owned(search_for: Vec<T>, replace_with: Vec<T>) -> Replacement<'a, T, S> {
+        Replacement {
+            search_for: Pattern::Literal(Cow::Owned(search_for)),
+            replace_with: Substitute::Items(Cow::Owned(replace_with)),
+            priority: 0,
+            limit: None,
+        }
+    }
+
+    /// Searches for a pattern made of elements that aren't all exact items.
+    pub fn elements(search_for: Vec<Element<T>>, replace_with: &'a [T]) -> Replacement<'a, T, S> {
+        Replacement {
+            search_for: Pattern::Elements(search_for),
+            replace_with: Substitute::Items(Cow::Borrowed(replace_with)),
+            priority: 0,
+            limit: None,
+        }
+    }
+
+    /// Sets the priority used by `MatchKind::Priority`. Higher priorities win.
+    pub fn with_priority(mut self, priority: u32) -> Replacement<'a, T, S> {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the maximum number of matches of this pattern to replace. After
+    /// that, the pattern is ignored.
+    pub fn with_limit(mut self, limit: usize) -> Replacement<'a, T, S> {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn with_substitute_fn(search_for: &'a [T], replace_with: Box<S::SubstituteFn>) -> Replacement<'a, T, S> {
         Replacement {
-            search_for: search_for,
-            replace_with: replace_with,
+            search_for: Pattern::Literal(Cow::Borrowed(search_for)),
+            replace_with: Substitute::Fn(replace_with),
+            priority: 0,
+            limit: None,
         }
     }
 }
 
-struct ReplaceState <'a, T: 'a + Ord> {
-    search_for: &'a [T],
-    replace_with: &'a [T],
-    candidates: RefCell<BTreeSet<usize>>,
+impl <'a, T: 'a + Clone> Replacement <'a, T> {
+    /// Replaces each match with the items produced by a function of the matched
+    /// items and the number of matches that were replaced before this one.
+    pub fn with_fn<F, R>(search_for: &'a [T], replace_with: F) -> Replacement<'a, T> where
+        F: Fn(&[T], usize) -> R + 'a,
+        R: IntoIterator<Item = T> {
+        let replace_with: Box<<Local as Sharing<'a, T>>::SubstituteFn> = Box::new(move |matched, match_index, out| {
+            out.extend(replace_with(matched, match_index))
+        });
+        Replacement::with_substitute_fn(search_for, replace_with)
+    }
+}
+
+impl <'a, T: 'a + Clone> Replacement <'a, T, Shared> {
+    /// Like `with_fn`, for `ReplaceBuilder::shared`.
+    pub fn with_shared_fn<F, R>(search_for: &'a [T], replace_with: F) -> Replacement<'a, T, Shared> where
+        F: Fn(&[T], usize) -> R + Send + Sync + 'a,
+        R: IntoIterator<Item = T> {
+        let replace_with: Box<<Shared as Sharing<'a, T>>::SubstituteFn> = Box::new(move |matched, match_index, out| {
+            out.extend(replace_with(matched, match_index))
+        });
+        Replacement::with_substitute_fn(search_for, replace_with)
+    }
+}
+
+/// How to choose between several patterns that match at the same index.
+///
+/// In every case, the match that starts first wins. Matches are never replaced
+/// until it is certain that no preferred match can complete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MatchKind {
+    /// The pattern declared first wins.
+    #[default]
+    LeftmostFirst,
+    /// The longest match wins, then the pattern declared first.
+    LeftmostLongest,
+    /// The pattern with the highest priority wins, then the pattern declared first.
+    Priority,
+}
+
+/// What happens when a partial match needs more than `max_buffer` items to be
+/// held back. This can only happen if a pattern is longer than `max_buffer`.
+///
+/// To fail with a `BufferOverflow` instead, see `ReplaceBuilder::checked`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Give up on the oldest partial match and pass on its first item. A
+    /// complete match that was waiting to see if a longer one completes is
+    /// replaced straight away instead.
+    Flush,
+    /// Patterns longer than `max_buffer` are never matched.
+    DropLongPatterns,
+}
+
+// What the searcher does past max_buffer. Only a `CheckedBuilder` fails,
+// since only its adapters can report the error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BufferPolicy {
+    Overflow(Overflow),
+    Fail,
+}
+
+/// The error of a `CheckedBuilder` once `max_buffer` is exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferOverflow {
+    pub max_buffer: usize,
+}
+
+impl fmt::Display for BufferOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a partial match needed more than {} items to be held back", self.max_buffer)
+    }
+}
+
+impl Error for BufferOverflow {}
+
+/// Configures how a set of `Replacement`s is applied to an iterator.
+pub struct ReplaceBuilder <'a, T: 'a + Clone, S: Sharing<'a, T> = Local> {
+    replacements: Vec<Replacement<'a, T, S>>,
+    match_kind: MatchKind,
+    eq: Option<Box<S::EqFn>>,
+    limit: Option<usize>,
+    max_buffer: Option<(usize, BufferPolicy)>,
+    allow_empty: bool,
+    order: Option<Order<T>>,
+}
+
+impl <'a, T> ReplaceBuilder <'a, T> where
+    T: 'a + PartialEq + Clone {
+
+    pub fn new(replacements: Vec<Replacement<'a, T>>) -> ReplaceBuilder<'a, T> {
+        ReplaceBuilder::with_replacements(replacements)
+    }
+
+    /// Compares items with a custom equality instead of `PartialEq`. It is called
+    /// with an item from a pattern and then an item from the iterator.
+    ///
+    /// This doesn't need to be an equivalence relation, but patterns are then
+    /// matched element by element, which is slower.
+    pub fn eq_by<F>(mut self, eq: F) -> ReplaceBuilder<'a, T> where
+        F: Fn(&T, &T) -> bool + 'a {
+        self.eq = Some(Box::new(eq));
+        self
+    }
+}
+
+impl <'a, T> ReplaceBuilder <'a, T, Shared> where
+    T: 'a + PartialEq + Clone {
+
+    /// A builder for replacements whose functions are all `Send + Sync`. It
+    /// compiles to a `Replacer` that is `Send` and `Sync` if the items are.
+    pub fn shared(replacements: Vec<Replacement<'a, T, Shared>>) -> ReplaceBuilder<'a, T, Shared> {
+        ReplaceBuilder::with_replacements(replacements)
+    }
+
+    /// Like `eq_by`, for a shared builder.
+    pub fn shared_eq_by<F>(mut self, eq: F) -> ReplaceBuilder<'a, T, Shared> where
+        F: Fn(&T, &T) -> bool + Send + Sync + 'a {
+        self.eq = Some(Box::new(eq));
+        self
+    }
+}
+
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    fn with_replacements(replacements: Vec<Replacement<'a, T, S>>) -> ReplaceBuilder<'a, T, S> {
+        ReplaceBuilder {
+            replacements,
+            match_kind: MatchKind::default(),
+            eq: None,
+            limit: None,
+            max_buffer: None,
+            allow_empty: false,
+            order: None,
+        }
+    }
+
+    pub fn match_kind(mut self, match_kind: MatchKind) -> ReplaceBuilder<'a, T, S> {
+        self.match_kind = match_kind;
+        self
+    }
+
+    /// Sets the maximum number of matches to replace, like `str::replacen`.
+    /// After that, the remaining items are passed through without buffering.
+    pub fn limit(mut self, limit: usize) -> ReplaceBuilder<'a, T, S> {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Caps the number of items that are held back while they might be part of
+    /// a match. Without it, up to the length of the longest pattern are held.
+    ///
+    /// The slice functions search the whole slice at once, so this has no
+    /// effect on them, except with `Overflow::DropLongPatterns`.
+    pub fn max_buffer(mut self, max_buffer: usize, overflow: Overflow) -> ReplaceBuilder<'a, T, S> {
+        self.max_buffer = Some((max_buffer, BufferPolicy::Overflow(overflow)));
+        self
+    }
+
+    /// Lets empty patterns match. Like `str::replace("", ..)`, an empty pattern
+    /// is then inserted before every item and at the end, except where another
+    /// match starts or inside a match. Otherwise they never match, and `build`
+    /// rejects them.
+    pub fn allow_empty_patterns(mut self) -> ReplaceBuilder<'a, T, S> {
+        self.allow_empty = true;
+        self
+    }
+
+    /// Uses `Ord` to find the next state of the compiled patterns with a
+    /// binary search, rather than comparing each item with every item that
+    /// could come next. That is worth it when there are many patterns over a
+    /// large alphabet, such as words or tokens. It must agree with `PartialEq`,
+    /// and has no effect with `eq_by` or `Element` patterns.
+    ///
+    /// The byte adapters of the builder, such as `replace_bytes` and `reader`,
+    /// always do this, and look up the first byte of a match in a table.
+    pub fn ordered(mut self) -> ReplaceBuilder<'a, T, S> where
+        T: Ord {
+        self.order = Some(Order::ord());
+        self
+    }
+
+    pub fn apply<I>(self, iter: I) -> Replace<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        self.compile().apply(iter)
+    }
+
+    /// Like `apply`, but each item is annotated with whether it was substituted.
+    pub fn segments<I>(self, iter: I) -> Segments<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        self.compile().segments(iter)
+    }
+
+    /// Finds the matches of the patterns without replacing them.
+    pub fn find_matches<I>(self, iter: I) -> FindMatches<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        self.compile().find_matches(iter)
+    }
+}
+
+/// A set of replacements compiled by `ReplaceBuilder::build`, with the same
+/// adapters as the builder.
+///
+/// It is immutable, and can be used for any number of iterators or streams,
+/// each of which gets its own search state. The compiled patterns are shared
+/// between them and with clones, which are cheap. A `Replacer<Shared>`, from
+/// `ReplaceBuilder::shared`, is also `Send` and `Sync` if the items are.
+pub struct Replacer <'a, T: 'a + Clone, S: Sharing<'a, T> = Local> {
+    matcher: Arc<Matcher<T>>,
+    eq: Option<Arc<Box<S::EqFn>>>,
+    replace_with: Arc<Vec<Substitute<'a, T, S>>>,
+    limit: Option<usize>,
+    pattern_limits: Vec<Option<usize>>,
+    max_buffer: Option<(usize, BufferPolicy)>,
+    insert: Vec<usize>,
 }
 
-impl <'a, T: 'a + Ord> ReplaceState <'a, T> {
-    fn new(search_for: &'a [T], replace_with: &'a [T]) -> ReplaceState<'a, T> {
-        ReplaceState {
-            search_for: search_for,
-            replace_with: replace_with,
-            candidates: RefCell::new(BTreeSet::new()),
+// Derived, it would need `S: Clone`
+impl <'a, T: Clone, S: Sharing<'a, T>> Clone for Replacer <'a, T, S> {
+    fn clone(&self) -> Replacer<'a, T, S> {
+        Replacer {
+            matcher: self.matcher.clone(),
+            eq: self.eq.clone(),
+            replace_with: self.replace_with.clone(),
+            limit: self.limit,
+            pattern_limits: self.pattern_limits.clone(),
+            max_buffer: self.max_buffer,
+            insert: self.insert.clone(),
         }
     }
 }
 
+/// A `ReplaceBuilder` whose `max_buffer` fails with a `BufferOverflow`, from
+/// `ReplaceBuilder::checked`.
+pub struct CheckedBuilder <'a, T: 'a + Clone, S: Sharing<'a, T> = Local> {
+    builder: ReplaceBuilder<'a, T, S>,
+}
 
-impl <'a, I, T> Replace <'a, I, T> where
-    I: Iterator<Item = T>,
-    T: Eq + Ord + Copy {
+/// A `Replacer` compiled by `CheckedBuilder::build`, with the same adapters as
+/// the builder.
+pub struct CheckedReplacer <'a, T: 'a + Clone, S: Sharing<'a, T> = Local> {
+    replacer: Replacer<'a, T, S>,
+}
 
-    fn adapt(iter: I, replace_states: Vec<ReplaceState<'a, T>>) -> Replace<'a, I, T> {
+impl <'a, T: Clone, S: Sharing<'a, T>> Clone for CheckedReplacer <'a, T, S> {
+    fn clone(&self) -> CheckedReplacer<'a, T, S> {
+        CheckedReplacer { replacer: self.replacer.clone() }
+    }
+}
+
+impl <'a, T, S> Replacer <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    pub fn apply<I>(&self, iter: I) -> Replace<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        let (searcher, replace_with) = self.searcher();
         Replace {
-            iter: iter,
+            iter: iter.into_iter().fuse(),
             buffer_out: VecDeque::new(),
-            buffer_in: Vec::new(),
-            replace_states: replace_states,
-            index: 0,
-            flushed_index: 0,
+            searcher,
+            replace_with,
         }
     }
 
-    fn fill_buffer(&mut self) {
-        'consume: while let Some(item) = self.iter.next() {
+    /// Like `apply`, but each item is annotated with whether it was substituted.
+    pub fn segments<I>(&self, iter: I) -> Segments<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        let (searcher, replace_with) = self.searcher();
+        Segments {
+            iter: iter.into_iter().fuse(),
+            buffer_out: VecDeque::new(),
+            searcher,
+            replace_with,
+            substituted: VecDeque::new(),
+        }
+    }
 
-            self.index += 1;
+    /// Finds the matches of the patterns without replacing them.
+    pub fn find_matches<I>(&self, iter: I) -> FindMatches<'a, I::IntoIter, T> where
+        I: IntoIterator<Item = T> {
+        FindMatches {
+            iter: iter.into_iter().fuse(),
+            matches: VecDeque::new(),
+            searcher: self.searcher().0,
+        }
+    }
 
-            // buffer all incoming items
-            self.buffer_in.push(item);
+    // A new search, and the substitutions for each pattern
+    fn searcher(&self) -> (Searcher<'a, T>, Arc<dyn Substitutes<T> + 'a>) {
+        let eq = self.eq.clone().map(|eq| eq as Arc<dyn Equality<T> + 'a>);
+        let searcher = Searcher::new(self.matcher.clone(), eq, self.limit, self.pattern_limits.clone(),
+                                     self.max_buffer, self.insert.clone());
+        (searcher, self.replace_with.clone())
+    }
+}
 
-            for replace_state in self.replace_states.iter() {
+/// A match of a pattern. Its `start` and `end` are indices of items in the
+/// source iterator, with `end` being exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub pattern_index: usize,
+    pub start: usize,
+    pub end: usize,
+}
 
-                let mut candidates = replace_state.candidates.borrow_mut();
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
 
-                // Prune existing partial match candidates that don't match the current item
-                let removes: Vec<_> = candidates.iter().cloned()
-                    .filter(|start_index| {
-                        replace_state.search_for[self.index - *start_index] != item
-                    }).collect();
-                for r in removes {
-                    candidates.remove(&r);
-                }
+    // Compiles the replacements without checking them
+    fn compile(self) -> Replacer<'a, T, S> {
+        let replacements = self.replacements;
+        let max_buffer = self.max_buffer;
+        let priorities: Vec<_> = replacements.iter().map(|r| r.priority).collect();
+        // an empty pattern never matches
+        let never = Pattern::Literal(Cow::Borrowed(&[]));
+        let patterns: Vec<_> = replacements.iter()
+            .map(|r| match max_buffer {
+                Some((max, BufferPolicy::Overflow(Overflow::DropLongPatterns))) if r.search_for.len() > max => &never,
+                _ => &r.search_for,
+            })
+            .collect();
+        let matcher = Matcher::new(&patterns, Ranking::new(self.match_kind, &priorities), self.eq.is_some(), self.order);
+        let pattern_limits = replacements.iter().map(|r| r.limit).collect();
+        // empty patterns are inserted between items instead, best ranked first
+        let mut insert: Vec<usize> = if self.allow_empty {
+            (0 .. replacements.len()).filter(|&i| replacements[i].search_for.len() == 0).collect()
+        } else {
+            Vec::new()
+        };
+        insert.sort_by_key(|&i| matcher.ranking().rank(i));
+        Replacer {
+            matcher: Arc::new(matcher),
+            eq: self.eq.map(Arc::new),
+            replace_with: Arc::new(replacements.into_iter().map(|r| r.replace_with).collect()),
+            limit: self.limit,
+            pattern_limits,
+            max_buffer,
+            insert,
+        }
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> ReplaceBuilder <'a, u8, S> {
+
+    // Bytes are ordered, and can index a table of transitions
+    fn compile_bytes(mut self) -> Replacer<'a, u8, S> {
+        self.order = Some(Order::bytes());
+        self.compile()
+    }
+}
+
+// Writes the substitutions for matches into the output buffer
+struct ReplaceSink<'s, 'a: 's, T: 'a + Clone> {
+    replace_with: &'s (dyn Substitutes<T> + 'a),
+    buffer_out: &'s mut VecDeque<T>,
+}
+
+impl <'s, 'a, T: Clone> Sink<T> for ReplaceSink<'s, 'a, T> {
+    fn item(&mut self, item: T) {
+        self.buffer_out.push_back(item);
+    }
+
+    fn matched(&mut self, m: Match, items: &[T], match_index: usize) {
+        self.replace_with.write(m.pattern_index, items, match_index, self.buffer_out);
+    }
+}
+
+impl <'a, I, T> Replace <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {
 
-                // Keep track of new partial match candidates
-                if replace_state.search_for[0] == item {
-                    candidates.insert(self.index);
+    // Searches until an item can be passed on, either straight away or through buffer_out
+    fn fill_buffer(&mut self) -> Option<T> {
+        let mut sink = ReplaceSink {
+            replace_with: &*self.replace_with,
+            buffer_out: &mut self.buffer_out,
+        };
+        while sink.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+            match self.iter.next() {
+                Some(item) => match self.searcher.try_pass(item, &mut sink) {
+                    Ok(Some(item)) => return Some(item),
+                    Ok(None) => {}
+                    // only the searcher of a `CheckedReplacer` can fail
+                    Err(_) => break,
+                },
+                None => {
+                    self.searcher.finish(&mut sink);
+                    break;
                 }
             }
+        }
+        None
+    }
 
-            let index = self.index;
-            let flush_index = self.calc_flushable_index();
+}
 
-            let matching_term = self.replace_states.iter().find(|replace_state| {
-                let mut candidates = replace_state.candidates.borrow_mut();
-                candidates.iter().cloned()
-                    .next()
-                    .into_iter()
-                    .find(|x| index - x + 1 == replace_state.search_for.len())
-                    .is_some()
-            });
+/// An item from the output of `Segments`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Segment<T> {
+    /// An item from the source iterator that wasn't part of a match.
+    Original(T),
+    /// An item that was substituted for a match of the pattern. A match that is
+    /// replaced with nothing doesn't produce any segments.
+    Replaced { pattern_index: usize, item: T },
+}
 
-            match matching_term {
-                None => {
-                    if flush_index > self.flushed_index {
-                        let unflushed = flush_index - self.flushed_index;
-                        let mut flush: VecDeque<_> = self.buffer_in.drain(0 .. unflushed).collect();
-                        self.buffer_out.append(&mut flush);
-                        self.flushed_index = flush_index;
-                        break 'consume;
-                    }
-                },
-                Some(replace_state) => {
-                    // A match! So replace it and clear all the partial matches
-                    for replace_state in self.replace_states.iter() {
-                        let mut candidates = replace_state.candidates.borrow_mut();
-                        candidates.clear();
+/// An iterator adapter like `Replace`, which tells substituted items apart
+/// from the original ones.
+pub struct Segments <'a, I, T: 'a + Clone> {
+    iter: Fuse<I>,
+    buffer_out: VecDeque<Segment<T>>,
+    searcher: Searcher<'a, T>,
+    replace_with: Arc<dyn Substitutes<T> + 'a>,
+    // reused for the output of each substitution
+    substituted: VecDeque<T>,
+}
+
+struct SegmentSink<'s, 'a: 's, T: 'a + Clone> {
+    replace_with: &'s (dyn Substitutes<T> + 'a),
+    buffer_out: &'s mut VecDeque<Segment<T>>,
+    substituted: &'s mut VecDeque<T>,
+}
+
+impl <'s, 'a, T: Clone> Sink<T> for SegmentSink<'s, 'a, T> {
+    fn item(&mut self, item: T) {
+        self.buffer_out.push_back(Segment::Original(item));
+    }
+
+    fn matched(&mut self, m: Match, items: &[T], match_index: usize) {
+        self.replace_with.write(m.pattern_index, items, match_index, self.substituted);
+        let pattern_index = m.pattern_index;
+        self.buffer_out.extend(self.substituted.drain(..).map(|item| Segment::Replaced { pattern_index, item }));
+    }
+}
+
+impl <'a, I, T> Iterator for Segments <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {
+
+    type Item = Segment<T>;
+
+    fn next(&mut self) -> Option<Segment<T>> {
+        if self.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+            let mut sink = SegmentSink {
+                replace_with: &*self.replace_with,
+                buffer_out: &mut self.buffer_out,
+                substituted: &mut self.substituted,
+            };
+            while sink.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+                match self.iter.next() {
+                    Some(item) => {
+                        if self.searcher.try_push(item, &mut sink).is_err() {
+                            break;
+                        }
                     }
-                    for &x in replace_state.replace_with.iter() {
-                        self.buffer_out.push_back(x);
+                    None => {
+                        self.searcher.finish(&mut sink);
+                        break;
                     }
-                    self.buffer_in.clear();
-                    self.flushed_index = self.index;
-                    break 'consume;
                 }
             }
         }
+        match self.buffer_out.pop_front() {
+            None if self.searcher.is_exhausted() => self.iter.next().map(Segment::Original),
+            segment => segment,
+        }
     }
+}
+
+impl <'a, I, T> FusedIterator for Segments <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {}
 
-    // the smallest index into buffer_in that doesn't contain a match
-    fn calc_flushable_index(&mut self) -> usize {
-        self.replace_states.iter().map(|replace_state| {
-            let mut candidates = replace_state.candidates.borrow_mut();
-            candidates.iter()
-                .next()
-                .map(|x| x - 1)
-                .unwrap_or(self.index)
-            }).min().unwrap_or(0)
+/// An iterator over the matches of patterns in another iterator.
+pub struct FindMatches <'a, I, T: 'a> {
+    iter: Fuse<I>,
+    matches: VecDeque<Match>,
+    searcher: Searcher<'a, T>,
+}
+
+impl <T> Sink<T> for VecDeque<Match> {
+    fn item(&mut self, _: T) {}
+
+    fn matched(&mut self, m: Match, _: &[T], _: usize) {
+        self.push_back(m);
     }
+}
+
+impl <'a, I, T> Iterator for FindMatches <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {
+
+    type Item = Match;
 
+    fn next(&mut self) -> Option<Match> {
+        while self.matches.is_empty() && !self.searcher.is_exhausted() {
+            match self.iter.next() {
+                Some(item) => {
+                    if self.searcher.try_push(item, &mut self.matches).is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    self.searcher.finish(&mut self.matches);
+                    break;
+                }
+            }
+        }
+        self.matches.pop_front()
+    }
 }
 
+impl <'a, I, T> FusedIterator for FindMatches <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {}
+
 
 pub trait ReplaceIter<'a, I, T> where
     I: Iterator<Item = T>,
-    T: Ord {
+    T: Clone {
 
     fn replace(self, search_for: &'a [T], replace_with: &'a [T]) -> Replace<'a, I, T>;
 
+    fn replace_with_fn<F, R>(self, search_for: &'a [T], replace_with: F) -> Replace<'a, I, T> where
+        F: Fn(&[T], usize) -> R + 'a,
+        R: IntoIterator<Item = T>;
+
     fn replace_all(self, replacements: Vec<Replacement<'a, T>>) -> Replace<'a, I, T>;
 
+    fn replace_by<F>(self, search_for: &'a [T], replace_with: &'a [T], eq: F) -> Replace<'a, I, T> where
+        F: Fn(&T, &T) -> bool + 'a;
+
+    fn replace_all_by<F>(self, replacements: Vec<Replacement<'a, T>>, eq: F) -> Replace<'a, I, T> where
+        F: Fn(&T, &T) -> bool + 'a;
+
+    fn find_matches(self, patterns: Vec<&'a [T]>) -> FindMatches<'a, I, T>;
+
 }
 
 impl <'a, I, T> ReplaceIter<'a, I, T> for I where
     I: Iterator<Item = T>,
-    T: Eq + Ord + Copy {
+    T: PartialEq + Clone {
 
-    ///
     fn replace(self, search_for: &'a [T], replace_with: &'a [T]) -> Replace<'a, I, T> {
-        let mut states = Vec::with_capacity(1);
-        states.push(ReplaceState::new(search_for, replace_with));
-        Replace::adapt(self, states)
+        ReplaceBuilder::new(vec![Replacement::new(search_for, replace_with)]).apply(self)
+    }
+
+    fn replace_with_fn<F, R>(self, search_for: &'a [T], replace_with: F) -> Replace<'a, I, T> where
+        F: Fn(&[T], usize) -> R + 'a,
+        R: IntoIterator<Item = T> {
+        ReplaceBuilder::new(vec![Replacement::with_fn(search_for, replace_with)]).apply(self)
     }
 
     fn replace_all(self, replacements: Vec<Replacement<'a, T>>) -> Replace<'a, I, T> {
-        let states = replacements.iter()
-            .map(|state| ReplaceState::new(state.search_for, state.replace_with))
-            .collect();
-        Replace::adapt(self, states)
+        ReplaceBuilder::new(replacements).apply(self)
+    }
+
+    fn replace_by<F>(self, search_for: &'a [T], replace_with: &'a [T], eq: F) -> Replace<'a, I, T> where
+        F: Fn(&T, &T) -> bool + 'a {
+        ReplaceBuilder::new(vec![Replacement::new(search_for, replace_with)]).eq_by(eq).apply(self)
+    }
+
+    fn replace_all_by<F>(self, replacements: Vec<Replacement<'a, T>>, eq: F) -> Replace<'a, I, T> where
+        F: Fn(&T, &T) -> bool + 'a {
+        ReplaceBuilder::new(replacements).eq_by(eq).apply(self)
+    }
+
+    fn find_matches(self, patterns: Vec<&'a [T]>) -> FindMatches<'a, I, T> {
+        let replacements = patterns.into_iter().map(|p| Replacement::new(p, &[])).collect();
+        ReplaceBuilder::new(replacements).find_matches(self)
     }
 }
 
 impl <'a, I, T> Iterator for Replace <'a, I, T> where
     I: Iterator<Item = T>,
-    T: Eq + Ord + Copy {
+    T: PartialEq + Clone {
 
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        if self.buffer_out.len() == 0 {
-            self.fill_buffer();
+        if self.buffer_out.is_empty() && !self.searcher.is_exhausted() {
+            if let Some(item) = self.fill_buffer() {
+                return Some(item);
+            }
+        }
+        match self.buffer_out.pop_front() {
+            None if self.searcher.is_exhausted() => self.iter.next(),
+            item => item,
         }
-        self.buffer_out.pop_front()
     }
 
 }
 
+impl <'a, I, T> FusedIterator for Replace <'a, I, T> where
+    I: Iterator<Item = T>,
+    T: PartialEq + Clone {}
+
 
 #[cfg(test)]
 mod tests {
@@ -240,4 +865,566 @@ mod tests {
         let v: Vec<u8> = b"abcabc".iter().cloned().replace_all(reps).collect();
         assert_eq!(v.as_slice(), b"_AB_c_AB_c");
     }
+
+    #[test]
+    pub fn test_leftmost_match_wins(){
+        let reps = vec![Replacement::new(b"bc", b"_BC_"),
+                        Replacement::new(b"abcd", b"_ABCD_")];
+        let v: Vec<u8> = b"abcdabcx".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"_ABCD_a_BC_x");
+    }
+
+    #[test]
+    pub fn test_match_via_failure_link(){
+        let reps = vec![Replacement::new(b"abcd", b"_ABCD_"),
+                        Replacement::new(b"bce", b"_BCE_")];
+        let v: Vec<u8> = b"abceabcd".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"a_BCE__ABCD_");
+    }
+
+    #[test]
+    pub fn test_thousands_of_patterns(){
+        let search: Vec<Vec<u32>> = (0..1000).map(|i| vec![2 * i, 2 * i + 1]).collect();
+        let replace: Vec<Vec<u32>> = (0..1000).map(|i| vec![10_000 + i]).collect();
+        let reps = search.iter().zip(replace.iter())
+            .map(|(s, r)| Replacement::new(s, r))
+            .collect();
+        let v: Vec<u32> = (0..2000).replace_all(reps).collect();
+        assert_eq!(v, (10_000..11_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn test_long_pattern_nearly_matching_everywhere(){
+        // every position starts a partial match that fails on the last item
+        let mut search = vec![0u8; 63];
+        search.push(1);
+        let mut input = vec![0u8; 100_000];
+        input.extend_from_slice(&search);
+        let v: Vec<u8> = input.iter().cloned().replace(&search, &[2]).collect();
+        assert_eq!(v.len(), 100_001);
+        assert_eq!(v.last(), Some(&2));
+    }
+
+    #[test]
+    pub fn test_partial_match_at_end() {
+        let v: Vec<u32> = vec![3,4,5].into_iter().replace(&[4,5,1], &[100,200]).collect();
+        assert_eq!(v, vec![3,4,5]);
+    }
+
+    #[test]
+    pub fn test_no_input_lost_for_pattern_prefixes_and_suffixes() {
+        let pattern = [4,5,6,7];
+        for i in 0 .. pattern.len() {
+            for input in [&pattern[..i], &pattern[i + 1 ..]].iter() {
+                let v: Vec<u32> = input.iter().cloned().replace(&pattern, &[100]).collect();
+                assert_eq!(v.as_slice(), *input);
+
+                let mut input = input.to_vec();
+                input.insert(0, 9);
+                let v: Vec<u32> = input.iter().cloned().replace(&pattern, &[100]).collect();
+                assert_eq!(v, input);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_pending_candidate_at_end(){
+        let reps = vec![Replacement::new(b"abcd", b"_ABCD_"),
+                        Replacement::new(b"ab", b"_AB_")];
+        let v: Vec<u8> = b"xab".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"x_AB_");
+
+        let reps = vec![Replacement::new(b"abcd", b"_ABCD_"),
+                        Replacement::new(b"ab", b"_AB_"),
+                        Replacement::new(b"c", b"_C_")];
+        let v: Vec<u8> = b"abc".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"_AB__C_");
+    }
+
+    #[test]
+    pub fn test_long_partial_match_is_not_scanned_again(){
+        // Each `a` is only reported once the long pattern fails, which is a thousand items later
+        thread_local!(static COMPARISONS: ::std::cell::Cell<usize> = const { ::std::cell::Cell::new(0) });
+        #[derive(Clone, Debug)]
+        struct Counted(u8);
+        impl PartialEq for Counted {
+            fn eq(&self, other: &Counted) -> bool {
+                COMPARISONS.with(|c| c.set(c.get() + 1));
+                self.0 == other.0
+            }
+        }
+        let mut long = vec![Counted(b'a'); 1000];
+        long.push(Counted(b'b'));
+        let reps = vec![Replacement::new(&long, &[]),
+                        Replacement::new(&[Counted(b'a')], &[Counted(b'x')])];
+        let n = 20_000;
+        let v: Vec<Counted> = ::std::iter::repeat_n(Counted(b'a'), n).replace_all(reps).collect();
+        let comparisons = COMPARISONS.with(|c| c.get());
+        assert_eq!(v.len(), n);
+        assert!(v.iter().all(|c| c.0 == b'x'));
+        assert!(comparisons < 10 * n, "{} comparisons for {} items", comparisons, n);
+    }
+
+    #[test]
+    pub fn test_ordered_with_many_patterns(){
+        thread_local!(static COMPARISONS: ::std::cell::Cell<usize> = const { ::std::cell::Cell::new(0) });
+        #[derive(Clone, Debug, Eq)]
+        struct Counted(u32);
+        impl PartialEq for Counted {
+            fn eq(&self, other: &Counted) -> bool {
+                self.cmp(other) == ::std::cmp::Ordering::Equal
+            }
+        }
+        impl PartialOrd for Counted {
+            fn partial_cmp(&self, other: &Counted) -> Option<::std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Counted {
+            fn cmp(&self, other: &Counted) -> ::std::cmp::Ordering {
+                COMPARISONS.with(|c| c.set(c.get() + 1));
+                self.0.cmp(&other.0)
+            }
+        }
+        // Ten thousand words that all start differently, so the root has a child for each
+        let n = 10_000;
+        let patterns: Vec<Vec<Counted>> = (0 .. n).map(|i| vec![Counted(i), Counted(i % 7)]).collect();
+        let reps = patterns.iter().map(|p| Replacement::new(p, &[])).collect();
+        let input = (0 .. n).rev().flat_map(|i| vec![Counted(i), Counted(i % 7), Counted(n)]);
+        let v: Vec<Counted> = ReplaceBuilder::new(reps).ordered().apply(input).collect();
+        let comparisons = COMPARISONS.with(|c| c.get());
+        assert_eq!(v.len(), n as usize);
+        assert!(v.iter().all(|c| c.0 == n));
+        // comparing with every child of the root would take n * n
+        assert!(comparisons < 100 * n as usize, "{} comparisons", comparisons);
+
+        let patterns: Vec<Vec<u8>> = (0 .. n).map(|i| format!("{}.", i).into_bytes()).collect();
+        let reps = || patterns.iter().map(|p| Replacement::new(p, b"#")).collect::<Vec<_>>();
+        let text: Vec<u8> = (0 .. 2 * n).flat_map(|i| format!("{}.", i * 7).into_bytes()).collect();
+        let expected: Vec<u8> = text.iter().cloned().replace_all(reps()).collect();
+        assert_eq!(ReplaceBuilder::new(reps()).replace_bytes(&text), expected);
+        assert_eq!(ReplaceBuilder::new(reps()).ordered().replace_slice(&text), expected);
+    }
+
+    #[test]
+    pub fn test_fused() {
+        let mut iter = vec![1,2].into_iter().replace(&[2,3], &[10]);
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    pub fn test_leftmost_longest(){
+        let reps = vec![Replacement::new(b"ab", b"_AB_"),
+                        Replacement::new(b"abc", b"_ABC_")];
+        let v: Vec<u8> = ReplaceBuilder::new(reps)
+            .match_kind(MatchKind::LeftmostLongest)
+            .apply(b"abcabd".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"_ABC__AB_d");
+    }
+
+    #[test]
+    pub fn test_priority(){
+        let reps = vec![Replacement::new(b"ab", b"_AB_"),
+                        Replacement::new(b"abc", b"_ABC_").with_priority(2),
+                        Replacement::new(b"abcd", b"_ABCD_").with_priority(1)];
+        let v: Vec<u8> = ReplaceBuilder::new(reps)
+            .match_kind(MatchKind::Priority)
+            .apply(b"abcdabx".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"_ABC_d_AB_x");
+    }
+
+    // A direct implementation of the match semantics, to check the automaton against
+    fn naive_replace(input: &[u8], patterns: &[(Vec<u8>, u32)], kind: MatchKind) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < input.len() {
+            let found = patterns.iter().enumerate()
+                .filter(|&(_, (p, _))| input[i ..].starts_with(p))
+                .min_by_key(|&(index, (p, priority))| match kind {
+                    MatchKind::LeftmostFirst => (0, 0, index),
+                    MatchKind::LeftmostLongest => (usize::MAX - p.len(), 0, index),
+                    MatchKind::Priority => (0, u32::MAX - *priority, index),
+                });
+            match found {
+                Some((index, (p, _))) => {
+                    out.extend(format!("<{}>", index).bytes());
+                    i += p.len();
+                }
+                None => {
+                    out.push(input[i]);
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    pub fn test_match_kinds_against_naive(){
+        let mut seed = 12345u32;
+        let mut random = |n: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) % n
+        };
+        for _ in 0 .. 500 {
+            let patterns: Vec<(Vec<u8>, u32)> = (0 .. 1 + random(5))
+                .map(|_| {
+                    let len = 1 + random(4);
+                    ((0 .. len).map(|_| b'a' + random(3) as u8).collect(), random(3))
+                })
+                .collect();
+            let input: Vec<u8> = (0 .. random(30)).map(|_| b'a' + random(3) as u8).collect();
+            let names: Vec<Vec<u8>> = (0 .. patterns.len())
+                .map(|i| format!("<{}>", i).into_bytes())
+                .collect();
+            for &kind in [MatchKind::LeftmostFirst, MatchKind::LeftmostLongest, MatchKind::Priority].iter() {
+                let reps = patterns.iter().zip(names.iter())
+                    .map(|((p, priority), name)| Replacement::new(p, name).with_priority(*priority))
+                    .collect();
+                let v: Vec<u8> = ReplaceBuilder::new(reps)
+                    .match_kind(kind)
+                    .apply(input.iter().cloned())
+                    .collect();
+                assert_eq!(v, naive_replace(&input, &patterns, kind),
+                    "{:?} {:?} {:?}", kind, patterns, input);
+
+                let reps = patterns.iter().zip(names.iter())
+                    .map(|((p, priority), name)| Replacement::new(p, name).with_priority(*priority))
+                    .collect();
+                let mut in_place = input.clone();
+                ReplaceBuilder::new(reps).match_kind(kind).replace_in_place(&mut in_place);
+                assert_eq!(in_place, v, "{:?} {:?} {:?}", kind, patterns, input);
+
+                // the same patterns, but matched element by element
+                let reps = patterns.iter().zip(names.iter())
+                    .map(|((p, priority), name)| {
+                        let elements = p.iter().cloned().map(Element::Exact).collect();
+                        Replacement::elements(elements, name).with_priority(*priority)
+                    })
+                    .collect();
+                let v: Vec<u8> = ReplaceBuilder::new(reps)
+                    .match_kind(kind)
+                    .apply(input.iter().cloned())
+                    .collect();
+                assert_eq!(v, naive_replace(&input, &patterns, kind),
+                    "{:?} {:?} {:?}", kind, patterns, input);
+            }
+        }
+    }
+
+    fn replace_from_config(config: &[(&str, &str)], input: &str) -> Replace<'static, ::std::vec::IntoIter<u8>, u8> {
+        let reps = config.iter()
+            .map(|&(s, r)| Replacement::owned(s.as_bytes().to_vec(), r.as_bytes().to_vec()))
+            .collect();
+        input.as_bytes().to_vec().into_iter().replace_all(reps)
+    }
+
+    #[test]
+    pub fn test_owned_replacements(){
+        let replace = replace_from_config(&[("ab", "_AB_"), ("c", "_C_")], "abcd");
+        let v: Vec<u8> = replace.collect();
+        assert_eq!(v.as_slice(), b"_AB__C_d");
+    }
+
+    #[test]
+    pub fn test_replace_with_fn_numbering(){
+        let v: Vec<u32> = vec![1,2,3,1,2,1,2].into_iter()
+            .replace_with_fn(&[1,2], |_, i| vec![100 + i as u32])
+            .collect();
+        assert_eq!(v, vec![100,3,101,102]);
+    }
+
+    #[test]
+    pub fn test_replace_all_with_fn(){
+        let table: [(u8, &[u8]); 2] = [(b'x', b"ex"), (b'y', b"why")];
+        let reps = vec![Replacement::with_fn(b"<>", |matched: &[u8], _| {
+                            let mut wrapped = b"[".to_vec();
+                            wrapped.extend(matched);
+                            wrapped.push(b']');
+                            wrapped
+                        }),
+                        Replacement::with_fn(b"$x", |m: &[u8], _| {
+                            table.iter().find(|e| e.0 == m[1]).unwrap().1.iter().cloned()
+                        }),
+                        Replacement::new(b"$y", b"Y")];
+        let v: Vec<u8> = b"a<>b$xc$y".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"a[<>]bexcY");
+    }
+
+    #[test]
+    pub fn test_wildcard_elements(){
+        let reps = vec![Replacement::elements(
+            vec![Element::Exact(0xFF), Element::Any, Element::Exact(0x00)], &[0xEE])];
+        let v: Vec<u8> = vec![1, 0xFF, 7, 0, 0xFF, 0xFF, 0, 0xFF, 1, 1].into_iter().replace_all(reps).collect();
+        assert_eq!(v, vec![1, 0xEE, 0xEE, 0xFF, 1, 1]);
+    }
+
+    #[test]
+    pub fn test_set_and_predicate_elements(){
+        fn is_digit(b: &u8) -> bool {
+            b.is_ascii_digit()
+        }
+        let reps = vec![Replacement::new(b"ab", b"_AB_"),
+                        Replacement::elements(vec![Element::OneOf(b"xy".to_vec()),
+                                                   Element::Pred(is_digit),
+                                                   Element::NoneOf(b"z".to_vec())], b"#")];
+        let v: Vec<u8> = b"x1ay2zab0y9bx".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"#y2z_AB_0#x");
+    }
+
+    #[test]
+    pub fn test_non_copy_items(){
+        let words = |s: &str| s.split(' ').map(String::from).collect::<Vec<_>>();
+        let search = words("hello world");
+        let replace = words("goodbye");
+        let v: Vec<String> = words("why hello hello world again").into_iter()
+            .replace(&search, &replace)
+            .collect();
+        assert_eq!(v, words("why hello goodbye again"));
+    }
+
+    #[test]
+    pub fn test_unmatched_items_are_not_cloned(){
+        use std::rc::Rc;
+        let input: Vec<Rc<u32>> = (0 .. 5).map(Rc::new).collect();
+        let search = [Rc::new(2), Rc::new(3)];
+        let replace = [Rc::new(10)];
+        let v: Vec<Rc<u32>> = input.iter().cloned().replace(&search, &replace).collect();
+        assert_eq!(v, vec![Rc::new(0), Rc::new(1), Rc::new(10), Rc::new(4)]);
+        // only the copies in the input and the output remain
+        assert_eq!(Rc::strong_count(&input[0]), 2);
+        assert_eq!(Rc::strong_count(&input[2]), 1);
+    }
+
+    #[test]
+    pub fn test_replace_ascii_case_insensitive(){
+        let v: Vec<u8> = b"Hello HELLO hello".iter().cloned()
+            .replace_by(b"hello", b"bye", |a, b| a.eq_ignore_ascii_case(b))
+            .collect();
+        assert_eq!(v.as_slice(), b"bye bye bye");
+    }
+
+    #[test]
+    pub fn test_replace_all_with_tolerance(){
+        let tolerance = 0.01;
+        let reps = vec![Replacement::new(&[1.0, 2.0], &[0.0]),
+                        Replacement::new(&[3.0], &[-1.0])];
+        let v: Vec<f64> = vec![1.001, 1.999, 2.5, 2.995].into_iter()
+            .replace_all_by(reps, |a: &f64, b: &f64| (a - b).abs() < tolerance)
+            .collect();
+        assert_eq!(v, vec![0.0, 2.5, -1.0]);
+    }
+
+    #[test]
+    pub fn test_replace_tokens_ignoring_spans(){
+        #[derive(Clone, Debug, PartialEq)]
+        struct Token { kind: &'static str, span: (usize, usize) }
+        let token = |kind, start| Token { kind, span: (start, start + 1) };
+        let search = [token("not", 0), token("not", 0)];
+        let v: Vec<Token> = vec![token("x", 0), token("not", 1), token("not", 2), token("y", 3)].into_iter()
+            .replace_by(&search, &[], |a, b| a.kind == b.kind)
+            .collect();
+        assert_eq!(v, vec![token("x", 0), token("y", 3)]);
+    }
+
+    #[test]
+    pub fn test_limit(){
+        let reps = vec![Replacement::new(b"ab", b"_AB_"), Replacement::new(b"c", b"_C_")];
+        let v: Vec<u8> = ReplaceBuilder::new(reps)
+            .limit(2)
+            .apply(b"abcabcab".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"_AB__C_abcab");
+
+        let reps = vec![Replacement::new(b"ab", b"_AB_")];
+        let v: Vec<u8> = ReplaceBuilder::new(reps)
+            .limit(0)
+            .apply(b"abcab".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"abcab");
+
+        let reps = vec![Replacement::new(b"ab", b"")];
+        let v: Vec<u8> = ReplaceBuilder::new(reps)
+            .limit(1)
+            .apply(b"abcab".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"cab");
+    }
+
+    #[test]
+    pub fn test_pattern_limits(){
+        let reps = vec![Replacement::new(b"ab", b"_AB_").with_limit(1),
+                        Replacement::new(b"a", b"_A_"),
+                        Replacement::new(b"ab", b"_ab_").with_limit(1)];
+        let v: Vec<u8> = b"abababa".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"_AB__A_b_A_b_A_");
+
+        let reps = vec![Replacement::new(b"abc", b"_ABC_").with_limit(1),
+                        Replacement::elements(vec![Element::Exact(b'c')], b"_C_").with_limit(2)];
+        let v: Vec<u8> = b"cabcabcc".iter().cloned().replace_all(reps).collect();
+        assert_eq!(v.as_slice(), b"_C__ABC_ab_C_c");
+    }
+
+    #[test]
+    pub fn test_limit_stops_buffering(){
+        let pulled = ::std::cell::Cell::new(0);
+        let source = vec![0, 1, 2, 1, 2, 3].into_iter().inspect(|_| pulled.set(pulled.get() + 1));
+        let mut replace = ReplaceBuilder::new(vec![Replacement::new(&[1, 2], &[100])])
+            .limit(1)
+            .apply(source);
+        assert_eq!(replace.next(), Some(0));
+        assert_eq!(replace.next(), Some(100));
+        // the 1 is returned without waiting to see if it starts another match
+        assert_eq!(replace.next(), Some(1));
+        assert_eq!(pulled.get(), 4);
+        assert_eq!(replace.collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    pub fn test_max_buffer_flush(){
+        let reps = || vec![Replacement::new(b"abcdef", b"1"), Replacement::new(b"cd", b"2")];
+        let v: Vec<u8> = b"abcdefcd".iter().cloned().replace_all(reps()).collect();
+        assert_eq!(v.as_slice(), b"12");
+        let v: Vec<u8> = ReplaceBuilder::new(reps())
+            .max_buffer(3, Overflow::Flush)
+            .apply(b"abcdefcd".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"ab2ef2");
+        let v: Vec<u8> = ReplaceBuilder::new(reps())
+            .max_buffer(3, Overflow::DropLongPatterns)
+            .apply(b"abcdefcd".iter().cloned())
+            .collect();
+        assert_eq!(v.as_slice(), b"ab2ef2");
+    }
+
+    #[test]
+    pub fn test_max_buffer_bounds_buffering(){
+        let pulled = ::std::cell::Cell::new(0);
+        let mut search = vec![0; 100];
+        search.push(1);
+        let source = ::std::iter::repeat_n(0, 1000).inspect(|_| pulled.set(pulled.get() + 1));
+        let mut replace = ReplaceBuilder::new(vec![Replacement::new(&search, &[2])])
+            .max_buffer(10, Overflow::Flush)
+            .apply(source);
+        for i in 0 .. 500 {
+            assert_eq!(replace.next(), Some(0));
+            assert_eq!(pulled.get(), i + 11);
+        }
+        assert_eq!(replace.count(), 500);
+    }
+
+    #[test]
+    pub fn test_max_buffer_error(){
+        let v: Vec<_> = ReplaceBuilder::new(vec![Replacement::new(b"abcdef", b"1")])
+            .checked(3)
+            .apply(b"abcxabcdx".iter().cloned())
+            .collect();
+        let mut expected: Vec<_> = b"abcx".iter().map(|&b| Ok(b)).collect();
+        expected.push(Err(BufferOverflow { max_buffer: 3 }));
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    pub fn test_find_matches(){
+        let found: Vec<Match> = b"abcabd".iter().cloned()
+            .find_matches(vec![b"ab", b"bd", b"c"])
+            .collect();
+        assert_eq!(found, vec![Match { pattern_index: 0, start: 0, end: 2 },
+                               Match { pattern_index: 2, start: 2, end: 3 },
+                               Match { pattern_index: 0, start: 3, end: 5 }]);
+    }
+
+    #[test]
+    pub fn test_find_matches_agrees_with_replace(){
+        let reps = || vec![Replacement::new(b"ab", b"1"),
+                           Replacement::new(b"abc", b"22"),
+                           Replacement::new(b"bcd", b"333")];
+        let input = b"abcdabcbcdab";
+        let found: Vec<Match> = ReplaceBuilder::new(reps())
+            .match_kind(MatchKind::LeftmostLongest)
+            .find_matches(input.iter().cloned())
+            .collect();
+        let replaced: Vec<u8> = ReplaceBuilder::new(reps())
+            .match_kind(MatchKind::LeftmostLongest)
+            .apply(input.iter().cloned())
+            .collect();
+        let mut rebuilt = Vec::new();
+        let mut i = 0;
+        for m in found {
+            rebuilt.extend_from_slice(&input[i .. m.start]);
+            rebuilt.extend(::std::iter::repeat_n(b'1' + m.pattern_index as u8, m.pattern_index + 1));
+            i = m.end;
+        }
+        rebuilt.extend_from_slice(&input[i ..]);
+        assert_eq!(rebuilt, replaced);
+    }
+
+    #[test]
+    pub fn test_segments(){
+        let reps = vec![Replacement::new(b"ab", b"X"),
+                        Replacement::new(b"c", b"YZ"),
+                        Replacement::new(b"d", b"")];
+        let segments: Vec<Segment<u8>> = ReplaceBuilder::new(reps)
+            .limit(3)
+            .segments(b"abecdc".iter().cloned())
+            .collect();
+        assert_eq!(segments, vec![Segment::Replaced { pattern_index: 0, item: b'X' },
+                                  Segment::Original(b'e'),
+                                  Segment::Replaced { pattern_index: 1, item: b'Y' },
+                                  Segment::Replaced { pattern_index: 1, item: b'Z' },
+                                  Segment::Original(b'c')]);
+    }
+
+    #[test]
+    pub fn test_replacer_is_reusable(){
+        let replacer = ReplaceBuilder::new(vec![Replacement::new(b"ab", b"X"), Replacement::new(b"b", b"Y")])
+            .limit(2)
+            .build()
+            .unwrap();
+        let first: Vec<u8> = replacer.apply(b"abbb".iter().cloned()).collect();
+        let second: Vec<u8> = replacer.apply(b"bab".iter().cloned()).collect();
+        assert_eq!(first, b"XYb");
+        assert_eq!(second, b"YX");
+        assert_eq!(replacer.replace_bytes(b"abbb"), b"XYb");
+        assert_eq!(replacer.clone().replace_slice(b"bab"), b"YX");
+    }
+
+    #[test]
+    pub fn test_local_functions_need_not_be_send(){
+        let count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let counted = count.clone();
+        let v: Vec<u8> = b"abab".iter().cloned()
+            .replace_with_fn(b"b", move |_, _| {
+                counted.set(counted.get() + 1);
+                vec![b'c']
+            })
+            .collect();
+        assert_eq!(v, b"acac");
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    pub fn test_replacer_shared_across_threads(){
+        fn assert_send_sync<S: Send + Sync>(_: &S) {}
+        let replacer = ReplaceBuilder::shared(vec![Replacement::with_shared_fn(b"a", |_, i| vec![b'0' + i as u8])])
+            .shared_eq_by(|a: &u8, b: &u8| a.eq_ignore_ascii_case(b))
+            .build()
+            .unwrap();
+        assert_send_sync(&replacer);
+        let outputs: Vec<Vec<u8>> = ::std::thread::scope(|scope| {
+            let handles: Vec<_> = (0 .. 4)
+                .map(|n| {
+                    let replacer = &replacer;
+                    scope.spawn(move || replacer.apply(::std::iter::repeat_n(b'A', n)).collect())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(outputs, vec![b"".to_vec(), b"0".to_vec(), b"01".to_vec(), b"012".to_vec()]);
+    }
 }
@@ -0,0 +1,207 @@
+//! Chooses between the matching engines, depending on the kinds of patterns.
+
+use std::borrow::Cow;
+use std::cmp::Reverse;
+
+use {Element, MatchKind};
+use automaton::{Automaton, Order, StateId, ROOT};
+use nfa::{Nfa, NfaState};
+
+/// A custom equality between an item from a pattern and an item being matched.
+/// It is kept out of the `Matcher`, which is shared by every search, so that
+/// only a `Shared` set of replacements needs it to be `Send + Sync`.
+pub trait Equality<T> {
+    fn eq(&self, pattern_item: &T, item: &T) -> bool;
+}
+
+impl <T, F: ?Sized + Fn(&T, &T) -> bool> Equality<T> for Box<F> {
+    fn eq(&self, pattern_item: &T, item: &T) -> bool {
+        self(pattern_item, item)
+    }
+}
+
+/// What a `Replacement` searches for.
+pub enum Pattern<'a, T: 'a + Clone> {
+    Literal(Cow<'a, [T]>),
+    Elements(Vec<Element<T>>),
+}
+
+impl <'a, T: Clone> Pattern<'a, T> {
+    pub fn len(&self) -> usize {
+        match *self {
+            Pattern::Literal(ref items) => items.len(),
+            Pattern::Elements(ref elements) => elements.len(),
+        }
+    }
+}
+
+/// Decides which of two matches starting at the same index is preferred.
+#[derive(Clone)]
+pub struct Ranking {
+    pub kind: MatchKind,
+    // lower ranks are preferred
+    ranks: Vec<usize>,
+}
+
+impl Ranking {
+    pub fn new(kind: MatchKind, priorities: &[u32]) -> Ranking {
+        // Declaration order breaks ties between equal priorities
+        let mut by_rank: Vec<usize> = (0 .. priorities.len()).collect();
+        if kind == MatchKind::Priority {
+            by_rank.sort_by_key(|&i| Reverse(priorities[i]));
+        }
+        let mut ranks = vec![0; priorities.len()];
+        for (rank, &i) in by_rank.iter().enumerate() {
+            ranks[i] = rank;
+        }
+        Ranking { kind, ranks }
+    }
+
+    pub fn rank(&self, pattern: usize) -> usize {
+        self.ranks[pattern]
+    }
+
+    /// Whether a match of pattern `a` with length `a_len` is preferred over one
+    /// of pattern `b` with length `b_len`.
+    pub fn prefers(&self, a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+        match self.kind {
+            MatchKind::LeftmostLongest if a_len != b_len => a_len > b_len,
+            _ => self.rank(a) < self.rank(b),
+        }
+    }
+}
+
+/// Literal patterns are compiled into an Aho-Corasick automaton. Any other
+/// elements, or a custom equality, need the slower `Nfa`, which tracks each
+/// pattern separately.
+pub enum Matcher<T> {
+    Literal(Automaton<T>),
+    Elements(Nfa<T>),
+}
+
+pub enum MatchState {
+    Literal(StateId),
+    Elements(NfaState),
+}
+
+impl <T: PartialEq + Clone> Matcher<T> {
+
+    /// With `custom_eq`, items are only compared with the `Equality` given to `next`.
+    pub fn new(patterns: &[&Pattern<T>], ranking: Ranking, custom_eq: bool, order: Option<Order<T>>) -> Matcher<T> {
+        let literals: Option<Vec<&[T]>> = patterns.iter()
+            .map(|pattern| match **pattern {
+                Pattern::Literal(ref items) => Some(&**items),
+                Pattern::Elements(_) => None,
+            })
+            .collect();
+        match literals {
+            Some(ref literals) if !custom_eq => Matcher::Literal(Automaton::new(literals, ranking, order)),
+            _ => {
+                let patterns = patterns.iter()
+                    .map(|pattern| match **pattern {
+                        Pattern::Literal(ref items) => items.iter().cloned().map(Element::Exact).collect(),
+                        Pattern::Elements(ref elements) => elements.clone(),
+                    })
+                    .collect();
+                Matcher::Elements(Nfa::new(patterns, ranking))
+            }
+        }
+    }
+
+    pub fn start(&self) -> MatchState {
+        match *self {
+            Matcher::Literal(_) => MatchState::Literal(ROOT),
+            Matcher::Elements(ref nfa) => MatchState::Elements(nfa.start()),
+        }
+    }
+
+    /// Forgets all partial matches.
+    pub fn reset(&self, state: &mut MatchState) {
+        match *state {
+            MatchState::Literal(ref mut id) => *id = ROOT,
+            MatchState::Elements(ref mut state) => state.reset(),
+        }
+    }
+
+    /// The items that a match can start with, if they are known.
+    pub fn start_items(&self) -> Option<Vec<&T>> {
+        match *self {
+            Matcher::Literal(ref automaton) => Some(automaton.start_items().collect()),
+            Matcher::Elements(_) => None,
+        }
+    }
+
+    /// The pattern, if there is only one and it is literal.
+    pub fn only_pattern(&self) -> Option<Vec<T>> {
+        match *self {
+            Matcher::Literal(ref automaton) => automaton.only_pattern(),
+            Matcher::Elements(_) => None,
+        }
+    }
+
+    pub fn ranking(&self) -> &Ranking {
+        match *self {
+            Matcher::Literal(ref automaton) => &automaton.ranking,
+            Matcher::Elements(ref nfa) => &nfa.ranking,
+        }
+    }
+
+    /// Advances the state by one item, and returns its new `depth`.
+    #[inline]
+    pub fn next(&self, state: &mut MatchState, item: &T, eq: Option<&dyn Equality<T>>) -> usize {
+        match (self, state) {
+            (Matcher::Literal(automaton), MatchState::Literal(id)) => {
+                *id = automaton.next_state(*id, item);
+                automaton.depth(*id)
+            }
+            (Matcher::Elements(nfa), MatchState::Elements(state)) => {
+                nfa.next(state, item, eq);
+                state.depth()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// The length of the longest partial or complete match ending at the current item.
+    #[inline]
+    pub fn depth(&self, state: &MatchState) -> usize {
+        match (self, state) {
+            (Matcher::Literal(automaton), MatchState::Literal(id)) => automaton.depth(*id),
+            (Matcher::Elements(_), MatchState::Elements(state)) => state.depth(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The complete matches ending at the current item, longest first, with
+    /// the best ranked enabled pattern of each length, as `(pattern_index, length)`.
+    /// Stops once `f` returns false.
+    pub fn each_match<E, F>(&self, state: &MatchState, enabled: E, f: F) where
+        E: Fn(usize) -> bool,
+        F: FnMut(usize, usize) -> bool {
+        match (self, state) {
+            (Matcher::Literal(automaton), MatchState::Literal(id)) => automaton.each_match(*id, enabled, f),
+            (Matcher::Elements(nfa), MatchState::Elements(state)) => nfa.each_match(state, enabled, f),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Forgets the partial matches that are longer than `max_depth`, as if
+    /// matching had started `max_depth` items ago.
+    pub fn rebase(&self, state: &mut MatchState, max_depth: usize) {
+        match (self, state) {
+            (Matcher::Literal(automaton), MatchState::Literal(id)) => *id = automaton.rebase(*id, max_depth),
+            (Matcher::Elements(nfa), MatchState::Elements(state)) => nfa.rebase(state, max_depth),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Whether the longest partial match could still complete a pattern that
+    /// would be preferred over a match of `pattern` with the same start.
+    pub fn can_improve(&self, state: &MatchState, pattern: usize) -> bool {
+        match (self, state) {
+            (Matcher::Literal(automaton), MatchState::Literal(id)) => automaton.can_improve(*id, pattern),
+            (Matcher::Elements(nfa), MatchState::Elements(state)) => nfa.can_improve(state, pattern),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Tracks the partial matches of each pattern separately, for patterns whose
+//! elements can't be compiled into an `Automaton`.
+//!
+//! Every pattern has a bitset in which bit `i` is set when the first `i + 1`
+//! elements of the pattern match the most recent items. Consuming an item
+//! shifts each bitset and clears the bits whose element doesn't match it.
+
+use std::cmp::Reverse;
+
+use Element;
+use matcher::{Equality, Ranking};
+
+pub struct Nfa<T> {
+    patterns: Vec<Vec<Element<T>>>,
+    // the index of the first word of each pattern's bitset
+    offsets: Vec<usize>,
+    words: usize,
+    // the patterns, longest first and then best ranked first
+    by_length: Vec<usize>,
+    pub ranking: Ranking,
+}
+
+pub struct NfaState {
+    active: Vec<u64>,
+    depth: usize,
+}
+
+impl NfaState {
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn reset(&mut self) {
+        for word in self.active.iter_mut() {
+            *word = 0;
+        }
+        self.depth = 0;
+    }
+}
+
+fn words_for(len: usize) -> usize {
+    len.div_ceil(64)
+}
+
+fn is_set(words: &[u64], bit: usize) -> bool {
+    words.get(bit / 64).is_some_and(|w| w & (1 << (bit % 64)) != 0)
+}
+
+impl <T: PartialEq> Nfa<T> {
+
+    pub fn new(patterns: Vec<Vec<Element<T>>>, ranking: Ranking) -> Nfa<T> {
+        let mut offsets = Vec::with_capacity(patterns.len());
+        let mut words = 0;
+        for pattern in &patterns {
+            offsets.push(words);
+            words += words_for(pattern.len());
+        }
+        let mut by_length: Vec<usize> = (0 .. patterns.len()).collect();
+        by_length.sort_by_key(|&i| (Reverse(patterns[i].len()), ranking.rank(i)));
+        Nfa { patterns, offsets, words, by_length, ranking }
+    }
+
+    pub fn start(&self) -> NfaState {
+        NfaState {
+            active: vec![0; self.words],
+            depth: 0,
+        }
+    }
+
+    fn bits<'s>(&self, state: &'s NfaState, pattern: usize) -> &'s [u64] {
+        let offset = self.offsets[pattern];
+        &state.active[offset .. offset + words_for(self.patterns[pattern].len())]
+    }
+
+    fn matches(element: &Element<T>, item: &T, eq: Option<&dyn Equality<T>>) -> bool {
+        match eq {
+            Some(eq) => element.matches_by(item, |a, b| eq.eq(a, b)),
+            None => element.matches(item),
+        }
+    }
+
+    pub fn next(&self, state: &mut NfaState, item: &T, eq: Option<&dyn Equality<T>>) {
+        state.depth = 0;
+        for (pattern, &offset) in self.patterns.iter().zip(self.offsets.iter()) {
+            let len = pattern.len();
+            let bits = &mut state.active[offset .. offset + words_for(len)];
+
+            // Every partial match gets one item longer, and a new one starts here
+            let mut carry = 1;
+            for word in bits.iter_mut() {
+                let next_carry = *word >> 63;
+                *word = (*word << 1) | carry;
+                carry = next_carry;
+            }
+
+            for (w, word) in bits.iter_mut().enumerate() {
+                let mut remaining = *word;
+                while remaining != 0 {
+                    let bit = remaining.trailing_zeros() as usize;
+                    remaining &= remaining - 1;
+                    let i = w * 64 + bit;
+                    if i >= len || !Nfa::matches(&pattern[i], item, eq) {
+                        *word &= !(1 << bit);
+                    } else if i + 1 > state.depth {
+                        state.depth = i + 1;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn each_match<E, F>(&self, state: &NfaState, enabled: E, mut f: F) where
+        E: Fn(usize) -> bool,
+        F: FnMut(usize, usize) -> bool {
+        let mut last_len = None;
+        for &index in &self.by_length {
+            let len = self.patterns[index].len();
+            if len == 0 || len > state.depth || last_len == Some(len) {
+                continue;
+            }
+            if enabled(index) && is_set(self.bits(state, index), len - 1) {
+                last_len = Some(len);
+                if !f(index, len) {
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn rebase(&self, state: &mut NfaState, max_depth: usize) {
+        if state.depth <= max_depth {
+            return;
+        }
+        state.depth = 0;
+        for (pattern, &offset) in self.patterns.iter().zip(self.offsets.iter()) {
+            let bits = &mut state.active[offset .. offset + words_for(pattern.len())];
+            for (w, word) in bits.iter_mut().enumerate() {
+                // bit i is a partial match of length i + 1
+                let first = w * 64;
+                if first >= max_depth {
+                    *word = 0;
+                } else if max_depth - first < 64 {
+                    *word &= (1 << (max_depth - first)) - 1;
+                }
+                if *word != 0 {
+                    state.depth = state.depth.max(first + 64 - word.leading_zeros() as usize);
+                }
+            }
+        }
+    }
+
+    pub fn can_improve(&self, state: &NfaState, pattern: usize) -> bool {
+        let depth = state.depth;
+        depth > 0 && self.patterns.iter().enumerate().any(|(index, p)| {
+            p.len() > depth
+                && is_set(self.bits(state, index), depth - 1)
+                && self.ranking.prefers(index, p.len(), pattern, depth)
+        })
+    }
+}
@@ -0,0 +1,151 @@
+//! Skipping ahead to the possible starts of matches in bytes.
+//!
+//! Most bytes can't start a match, so rather than feeding every byte to the
+//! automaton, the bytes that patterns start with are searched for directly:
+//! with `memchr` for up to three distinct bytes, or a lookup table otherwise.
+//! A single pattern is searched for as a whole with `memmem`, which uses the
+//! Two-Way algorithm and so can skip over most of the bytes for a long pattern.
+//! This is only done for literal patterns, and only between matches, so the
+//! output is the same as without it.
+
+use std::sync::Arc;
+
+use memchr::{memchr, memchr2, memchr3};
+use memchr::memmem::Finder;
+
+use {Replacer, Sharing, Substitutes};
+use matcher::Matcher;
+use searcher::{Searcher, SkipFn};
+
+/// The searcher for a byte-specific adapter, with a prefilter if possible.
+pub fn byte_searcher<'a, S>(replacer: &Replacer<'a, u8, S>) -> (Searcher<'a, u8>, Arc<dyn Substitutes<u8> + 'a>) where
+    S: Sharing<'a, u8> {
+    let (mut searcher, replace_with) = replacer.searcher();
+    if let Some(prefilter) = byte_prefilter(searcher.matcher()) {
+        searcher.set_prefilter(prefilter);
+    }
+    (searcher, replace_with)
+}
+
+fn byte_prefilter<'a>(matcher: &Matcher<u8>) -> Option<SkipFn<'a, u8>> {
+    let starts = matcher.start_items()?.into_iter().cloned().collect();
+    Some(match matcher.only_pattern().filter(|pattern| pattern.len() > 1) {
+        Some(pattern) => single_prefilter(&pattern),
+        None => prefilter(starts),
+    })
+}
+
+// An occurrence can start in the last bytes and be completed by the bytes after
+// them, so those are never skipped
+fn single_prefilter<'a>(pattern: &[u8]) -> SkipFn<'a, u8> {
+    let finder = Finder::new(pattern).into_owned();
+    let tail = pattern.len() - 1;
+    Box::new(move |bytes: &[u8]| finder.find(bytes).unwrap_or(bytes.len().saturating_sub(tail)))
+}
+
+fn prefilter<'a>(starts: Vec<u8>) -> SkipFn<'a, u8> {
+    match starts[..] {
+        [] => Box::new(|bytes: &[u8]| bytes.len()),
+        [a] => Box::new(move |bytes: &[u8]| memchr(a, bytes).unwrap_or(bytes.len())),
+        [a, b] => Box::new(move |bytes: &[u8]| memchr2(a, b, bytes).unwrap_or(bytes.len())),
+        [a, b, c] => Box::new(move |bytes: &[u8]| memchr3(a, b, c, bytes).unwrap_or(bytes.len())),
+        _ => {
+            let mut table = [false; 256];
+            for byte in starts {
+                table[byte as usize] = true;
+            }
+            Box::new(move |bytes: &[u8]| bytes.iter().position(|&b| table[b as usize]).unwrap_or(bytes.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Element, MatchKind, ReplaceBuilder, ReplaceIter, Replacement};
+
+    #[test]
+    pub fn test_prefilter_skips_to_starts(){
+        assert_eq!(prefilter(vec![])(b"abc"), 3);
+        assert_eq!(prefilter(vec![b'c'])(b"abcabc"), 2);
+        assert_eq!(prefilter(vec![b'x', b'b'])(b"abcabc"), 1);
+        assert_eq!(prefilter(vec![b'x', b'y', b'z'])(b"abcabc"), 6);
+        assert_eq!(prefilter(vec![b'w', b'x', b'y', b'c'])(b"abcabc"), 2);
+    }
+
+    #[test]
+    pub fn test_single_prefilter_keeps_partial_occurrence(){
+        let skip = single_prefilter(b"abcd");
+        assert_eq!(skip(b"xxabcdxx"), 2);
+        assert_eq!(skip(b"xxxxxxab"), 5);
+        assert_eq!(skip(b"ab"), 0);
+    }
+
+    #[test]
+    pub fn test_only_literals_are_prefiltered(){
+        let literals = ReplaceBuilder::new(vec![Replacement::new(b"ab", b"x")]).build().unwrap();
+        assert_eq!(byte_prefilter(&literals.matcher).unwrap()(b"xxxab"), 3);
+        let elements = ReplaceBuilder::new(vec![Replacement::elements(vec![Element::Any], b"x")]).build().unwrap();
+        assert!(byte_prefilter(&elements.matcher).is_none());
+    }
+
+    #[test]
+    pub fn test_long_boundary(){
+        let boundary: Vec<u8> = (0 .. 64).map(|i| b'-' + (i % 2) as u8).collect();
+        let mut body = Vec::new();
+        for part in 0 .. 200u8 {
+            body.extend_from_slice(&boundary);
+            body.extend(::std::iter::repeat_n(b'-', 63));
+            body.push(part);
+        }
+        let expected: Vec<u8> = body.iter().cloned().replace(&boundary, b"|").collect();
+        assert_eq!(expected.len(), 200 * 65);
+
+        let reps = || vec![Replacement::new(&boundary, b"|")];
+        assert_eq!(ReplaceBuilder::new(reps()).replace_bytes(&body), expected);
+        let mut read = Vec::new();
+        ::std::io::Read::read_to_end(&mut ReplaceBuilder::new(reps()).reader(&body[..]), &mut read).unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    pub fn test_byte_paths_agree_with_generic(){
+        let mut seed = 7u32;
+        let mut random = |n: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) % n
+        };
+        for round in 0 .. 300 {
+            let alphabet = 2 + random(6);
+            let patterns: Vec<Vec<u8>> = (0 .. 1 + random(5))
+                .map(|_| (0 .. 1 + random(4)).map(|_| b'a' + random(alphabet) as u8).collect())
+                .collect();
+            let input: Vec<u8> = (0 .. random(60)).map(|_| b'a' + random(alphabet + 2) as u8).collect();
+            let kind = [MatchKind::LeftmostFirst, MatchKind::LeftmostLongest][round % 2];
+            let builder = || {
+                let reps = patterns.iter().map(|p| Replacement::new(p, b"<>")).collect();
+                let builder = ReplaceBuilder::new(reps).match_kind(kind);
+                if round % 3 == 0 { builder.limit(2) } else { builder }
+            };
+            let expected: Vec<u8> = builder().apply(input.iter().cloned()).collect();
+            assert_eq!(builder().replace_bytes(&input), expected, "{:?} {:?}", patterns, input);
+
+            let mut reader = builder().reader(&input[..]);
+            let mut read = Vec::new();
+            ::std::io::Read::read_to_end(&mut reader, &mut read).unwrap();
+            assert_eq!(read, expected, "{:?} {:?}", patterns, input);
+
+            let mut writer = builder().writer(Vec::new());
+            for chunk in input.chunks(7) {
+                ::std::io::Write::write_all(&mut writer, chunk).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), expected, "{:?} {:?}", patterns, input);
+        }
+    }
+
+    #[test]
+    pub fn test_no_prefilter_for_elements(){
+        let reps = vec![Replacement::elements(vec![Element::Any, Element::Exact(b'b')], b"_")];
+        assert_eq!(ReplaceBuilder::new(reps).replace_bytes(b"abcb").as_slice(), b"__");
+    }
+}
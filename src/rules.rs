@@ -0,0 +1,356 @@
+//! Rule sets that can be loaded from config files, enabled by the `serde`
+//! feature.
+//!
+//! A rule set is a table with a list of `rules`, so it has the same shape in
+//! JSON, TOML and YAML:
+//!
+//! ```toml
+//! [[rules]]
+//! search = "colour"
+//! replace = "color"
+//!
+//! [[rules]]
+//! search = { hex = "0d 0a" }
+//! replace = "\\n"
+//! max_count = 10
+//! ```
+//!
+//! With `Bytes` for the search and replacement, as above, they can be written
+//! with escapes or in hex. With any other type, such as `String` or `Vec<T>`,
+//! they are deserialized as that type.
+
+use std::error::Error;
+use std::fmt;
+
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use {BuildError, MatchKind, ReplaceBuilder, Replacement, Replacer, Shared, Sharing};
+
+/// A list of rules, in the order that they are declared in.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet<S> {
+    pub rules: Vec<Rule<S>>,
+}
+
+/// One rule of a `RuleSet`, which becomes a `Replacement`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule<S> {
+    pub search: S,
+    pub replace: S,
+    /// See `Replacement::with_priority`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    /// The maximum number of matches to replace. See `Replacement::with_limit`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+}
+
+impl <S> RuleSet<S> {
+    /// The rules as the `Replacement`s that `replace_all` takes.
+    pub fn into_replacements<'a, T>(self) -> Vec<Replacement<'a, T>> where
+        S: Into<Vec<T>>,
+        T: 'a + Clone {
+        self.replacements()
+    }
+
+    /// A builder for the rules. If any rule has a priority, they are matched
+    /// with `MatchKind::Priority`.
+    pub fn into_builder<'a, T>(self) -> ReplaceBuilder<'a, T> where
+        S: Into<Vec<T>>,
+        T: 'a + PartialEq + Clone {
+        self.builder()
+    }
+
+    /// Validates and compiles the rules. The `pattern_index` of an error is
+    /// the index of the rule in `rules`.
+    ///
+    /// Rules have no functions, so the `Replacer` is `Send` and `Sync` if the
+    /// items are.
+    pub fn build<'a, T>(self) -> Result<Replacer<'a, T, Shared>, BuildError> where
+        S: Into<Vec<T>>,
+        T: 'a + PartialEq + Clone {
+        self.builder().build()
+    }
+
+    fn replacements<'a, T, Sh>(self) -> Vec<Replacement<'a, T, Sh>> where
+        S: Into<Vec<T>>,
+        T: 'a + Clone,
+        Sh: Sharing<'a, T> {
+        self.rules.into_iter()
+            .map(|rule| {
+                let mut replacement = Replacement::owned(rule.search.into(), rule.replace.into());
+                if let Some(priority) = rule.priority {
+                    replacement = replacement.with_priority(priority);
+                }
+                if let Some(max_count) = rule.max_count {
+                    replacement = replacement.with_limit(max_count);
+                }
+                replacement
+            })
+            .collect()
+    }
+
+    fn builder<'a, T, Sh>(self) -> ReplaceBuilder<'a, T, Sh> where
+        S: Into<Vec<T>>,
+        T: 'a + PartialEq + Clone,
+        Sh: Sharing<'a, T> {
+        let by_priority = self.rules.iter().any(|rule| rule.priority.is_some());
+        let builder = ReplaceBuilder::with_replacements(self.replacements());
+        if by_priority {
+            builder.match_kind(MatchKind::Priority)
+        } else {
+            builder
+        }
+    }
+}
+
+/// The bytes of a byte rule.
+///
+/// They are deserialized from a string, in which `\n`, `\r`, `\t`, `\0`, `\\`,
+/// `\"`, `\'` and `\xHH` are escapes and other chars stand for their UTF-8
+/// bytes; from a table with a single `hex` string, which can have whitespace
+/// between the digits; or from a list of numbers. They are serialized as an
+/// escaped string.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+/// The error for a malformed escaped or hex string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseBytesError {
+    /// A backslash at this byte offset doesn't start a valid escape.
+    InvalidEscape { offset: usize },
+    /// The char at this byte offset isn't a hex digit.
+    InvalidHexDigit { offset: usize },
+    /// The last hex digit doesn't have a pair.
+    OddHexDigits,
+}
+
+impl fmt::Display for ParseBytesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseBytesError::InvalidEscape { offset } => write!(f, "invalid escape at offset {}", offset),
+            ParseBytesError::InvalidHexDigit { offset } => write!(f, "invalid hex digit at offset {}", offset),
+            ParseBytesError::OddHexDigits => write!(f, "odd number of hex digits"),
+        }
+    }
+}
+
+impl Error for ParseBytesError {}
+
+impl Bytes {
+    /// Parses a string with escapes, as described for `Bytes`.
+    pub fn escaped(s: &str) -> Result<Bytes, ParseBytesError> {
+        let mut bytes = Vec::with_capacity(s.len());
+        let mut chars = s.char_indices();
+        while let Some((offset, c)) = chars.next() {
+            if c != '\\' {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+            let invalid = ParseBytesError::InvalidEscape { offset };
+            let byte = match chars.next().map(|(_, c)| c) {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('0') => 0,
+                Some('\\') => b'\\',
+                Some('"') => b'"',
+                Some('\'') => b'\'',
+                Some('x') => {
+                    let hi = chars.next().and_then(|(_, c)| c.to_digit(16)).ok_or(invalid)?;
+                    let lo = chars.next().and_then(|(_, c)| c.to_digit(16)).ok_or(invalid)?;
+                    (hi * 16 + lo) as u8
+                }
+                _ => return Err(invalid),
+            };
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+
+    /// Parses pairs of hex digits, ignoring whitespace.
+    pub fn hex(s: &str) -> Result<Bytes, ParseBytesError> {
+        let mut bytes = Vec::with_capacity(s.len() / 2);
+        let mut high = None;
+        for (offset, c) in s.char_indices().filter(|&(_, c)| !c.is_whitespace()) {
+            let digit = c.to_digit(16).ok_or(ParseBytesError::InvalidHexDigit { offset })? as u8;
+            match high.take() {
+                Some(high) => bytes.push(high << 4 | digit),
+                None => high = Some(digit),
+            }
+        }
+        match high {
+            Some(_) => Err(ParseBytesError::OddHexDigits),
+            None => Ok(Bytes(bytes)),
+        }
+    }
+
+    /// The bytes as a string that `escaped` parses back to them.
+    pub fn to_escaped(&self) -> String {
+        let mut s = String::with_capacity(self.0.len());
+        for &byte in &self.0 {
+            match byte {
+                b'\n' => s.push_str("\\n"),
+                b'\r' => s.push_str("\\r"),
+                b'\t' => s.push_str("\\t"),
+                0 => s.push_str("\\0"),
+                b'\\' => s.push_str("\\\\"),
+                b'"' => s.push_str("\\\""),
+                0x20 ..= 0x7e => s.push(byte as char),
+                _ => s.push_str(&format!("\\x{:02x}", byte)),
+            }
+        }
+        s
+    }
+}
+
+impl From<Bytes> for Vec<u8> {
+    fn from(bytes: Bytes) -> Vec<u8> {
+        bytes.0
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_escaped())
+    }
+}
+
+impl <'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl <'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an escaped string, a table with a hex string, or a list of bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Bytes, E> {
+        Bytes::escaped(s).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(bytes.to_vec()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Bytes, A::Error> {
+        let bytes = match map.next_key::<String>()? {
+            Some(ref key) if key == "hex" => {
+                let hex: String = map.next_value()?;
+                Bytes::hex(&hex).map_err(de::Error::custom)?
+            }
+            Some(key) => return Err(de::Error::unknown_field(&key, &["hex"])),
+            None => return Err(de::Error::missing_field("hex")),
+        };
+        match map.next_key::<String>()? {
+            Some(key) => Err(de::Error::unknown_field(&key, &["hex"])),
+            None => Ok(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use serde_yaml;
+    use toml;
+
+    #[test]
+    pub fn test_load_json(){
+        let json = r#"{"rules": [
+            {"search": "a\\tb", "replace": {"hex": "00 ff"}},
+            {"search": [120, 121], "replace": "\\x7a", "max_count": 1}
+        ]}"#;
+        let rules: RuleSet<Bytes> = serde_json::from_str(json).unwrap();
+        assert_eq!(rules.rules[0].search, Bytes(b"a\tb".to_vec()));
+        assert_eq!(rules.rules[0].replace, Bytes(vec![0, 0xff]));
+        let replacer = rules.build().unwrap();
+        assert_eq!(replacer.replace_bytes(b"a\tb xy xy"), b"\x00\xff z xy");
+    }
+
+    #[test]
+    pub fn test_load_toml(){
+        let text = r#"
+            [[rules]]
+            search = "colour"
+            replace = "color"
+
+            [[rules]]
+            search = { hex = "0d0a" }
+            replace = '\n'
+            priority = 1
+        "#;
+        let rules: RuleSet<Bytes> = toml::from_str(text).unwrap();
+        let builder = rules.into_builder();
+        assert_eq!(builder.replace_bytes(b"colour\r\n"), b"color\n");
+    }
+
+    #[test]
+    pub fn test_load_yaml(){
+        let text = "rules:\n  - search: cat\n    replace: dog\n  - search: ca\n    replace: xx\n    priority: 2\n";
+        let rules: RuleSet<String> = serde_yaml::from_str(text).unwrap();
+        assert_eq!(rules.into_builder().replace_bytes(b"cat"), b"xxt");
+    }
+
+    #[test]
+    pub fn test_load_items(){
+        let rules: RuleSet<Vec<u32>> = serde_json::from_str(r#"{"rules": [{"search": [1, 2], "replace": [3]}]}"#).unwrap();
+        let replaced: Vec<u32> = rules.build().unwrap().apply(vec![1, 2, 1]).collect();
+        assert_eq!(replaced, vec![3, 1]);
+    }
+
+    #[test]
+    pub fn test_rule_errors(){
+        let result = serde_json::from_str::<RuleSet<Bytes>>(r#"{"rules": [{"search": "\\q", "replace": ""}]}"#);
+        assert!(result.unwrap_err().to_string().contains("invalid escape at offset 0"));
+        let result = serde_json::from_str::<RuleSet<Bytes>>(r#"{"rules": [{"search": {"hex": "abc"}, "replace": ""}]}"#);
+        assert!(result.unwrap_err().to_string().contains("odd number of hex digits"));
+        let result = serde_json::from_str::<RuleSet<Bytes>>(r#"{"rules": [{"search": "a", "replace": "", "max": 1}]}"#);
+        assert!(result.is_err());
+
+        let rules: RuleSet<Bytes> = serde_json::from_str(r#"{"rules": [
+            {"search": "a", "replace": "b"},
+            {"search": "", "replace": "c"}
+        ]}"#).unwrap();
+        assert_eq!(rules.build::<u8>().err(), Some(BuildError::EmptyPattern { pattern_index: 1 }));
+    }
+
+    #[test]
+    pub fn test_escaped_round_trip(){
+        let bytes = Bytes((0 ..= 255).collect());
+        assert_eq!(Bytes::escaped(&bytes.to_escaped()), Ok(bytes.clone()));
+        let rules = RuleSet { rules: vec![Rule { search: bytes, replace: Bytes(b"\"x\"".to_vec()), priority: None, max_count: Some(2) }] };
+        let json = serde_json::to_string(&rules).unwrap();
+        assert_eq!(serde_json::from_str::<RuleSet<Bytes>>(&json).unwrap(), rules);
+    }
+
+    #[test]
+    pub fn test_built_rules_are_send_sync(){
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Replacer<'static, u8, Shared>>();
+
+        let rules: RuleSet<Bytes> = serde_json::from_str(r#"{"rules": [{"search": "ab", "replace": "x"}]}"#).unwrap();
+        let replacer = rules.build().unwrap();
+        let replaced = ::std::thread::spawn(move || replacer.replace_bytes(b"abcab")).join().unwrap();
+        assert_eq!(replaced, b"xcx");
+    }
+}
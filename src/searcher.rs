@@ -0,0 +1,500 @@
+//! The per-stream state of a search, shared by all of the adapters.
+//!
+//! Items are pushed in one at a time and are buffered until it is known
+//! whether they are part of a match. They are then passed on to a `Sink`,
+//! either one by one or as a whole match.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::sync::Arc;
+
+use {BufferOverflow, BufferPolicy, Match, Overflow};
+use matcher::{Equality, Matcher, MatchState};
+
+/// Receives the result of a search, in order.
+pub trait Sink<T> {
+    /// An item that isn't part of any match.
+    fn item(&mut self, item: T);
+
+    /// A match, with the matched items and the number of matches before it.
+    fn matched(&mut self, m: Match, items: &[T], match_index: usize);
+
+    /// A run of items that aren't part of any match.
+    fn items(&mut self, items: &[T]) where T: Clone {
+        for item in items {
+            self.item(item.clone());
+        }
+    }
+}
+
+/// Finds the index of the first item that might start a match, or the length
+/// if there is none.
+pub type SkipFn<'a, T> = Box<dyn Fn(&[T]) -> usize + 'a>;
+
+pub struct Searcher<'a, T> {
+    scanner: Scanner<'a, T>,
+    buffer_in: VecDeque<T>,
+    // the index of the first item in buffer_in
+    flushed_index: usize,
+    // skips over items that can't start a match, when nothing is in progress
+    prefilter: Option<SkipFn<'a, T>>,
+    max_buffer: Option<(usize, BufferPolicy)>,
+    overflow: Option<BufferOverflow>,
+    // empty patterns, best ranked first, which match wherever no other match starts
+    insert: Vec<usize>,
+    // finish can be called more than once at the same index
+    inserted_at: Option<usize>,
+}
+
+// Decides on the matches, given the items one at a time by index
+struct Scanner<'a, T> {
+    matcher: Arc<Matcher<T>>,
+    eq: Option<Arc<dyn Equality<T> + 'a>>,
+    state: MatchState,
+    // the length of the longest partial or complete match in the state
+    depth: usize,
+    // the best complete match found so far that hasn't been reported yet,
+    // followed by the best one starting after it, and so on
+    candidates: VecDeque<Match>,
+    // the index of the next item to feed to the matcher
+    index: usize,
+    // the number of matches reported so far, in total and for each pattern
+    matches: usize,
+    pattern_matches: Vec<usize>,
+    limit: Option<usize>,
+    pattern_limits: Vec<Option<usize>>,
+    // once no more matches can be reported, items are passed straight through
+    exhausted: bool,
+}
+
+impl <'a, T: PartialEq + Clone> Searcher<'a, T> {
+
+    pub fn new(matcher: Arc<Matcher<T>>, eq: Option<Arc<dyn Equality<T> + 'a>>, limit: Option<usize>,
+               pattern_limits: Vec<Option<usize>>, max_buffer: Option<(usize, BufferPolicy)>,
+               insert: Vec<usize>) -> Searcher<'a, T> {
+        let exhausted = limit == Some(0) || pattern_limits.iter().all(|&l| l == Some(0));
+        Searcher {
+            scanner: Scanner {
+                state: matcher.start(),
+                matcher,
+                eq,
+                depth: 0,
+                candidates: VecDeque::new(),
+                index: 0,
+                matches: 0,
+                pattern_matches: vec![0; pattern_limits.len()],
+                limit,
+                pattern_limits,
+                exhausted,
+            },
+            buffer_in: VecDeque::new(),
+            flushed_index: 0,
+            prefilter: None,
+            max_buffer,
+            overflow: None,
+            insert,
+            inserted_at: None,
+        }
+    }
+
+    pub fn matcher(&self) -> &Matcher<T> {
+        &self.scanner.matcher
+    }
+
+    pub fn set_prefilter(&mut self, prefilter: SkipFn<'a, T>) {
+        self.prefilter = Some(prefilter);
+    }
+
+    /// Whether the limits have been reached, so no more matches can be found.
+    pub fn is_exhausted(&self) -> bool {
+        self.scanner.exhausted
+    }
+
+    /// The error if `try_push` has failed, after which it always fails.
+    pub fn overflow(&self) -> Option<BufferOverflow> {
+        self.overflow
+    }
+
+    /// Pushes an item, failing if more than `max_buffer` items need to be held
+    /// back and the searcher is for a `CheckedReplacer`.
+    pub fn try_push<S: Sink<T>>(&mut self, item: T, sink: &mut S) -> Result<(), BufferOverflow> {
+        if let Some(item) = self.try_pass(item, sink)? {
+            sink.item(item);
+        }
+        Ok(())
+    }
+
+    /// Like `try_push`, but if the item can be passed on straight away, and
+    /// nothing else is passed to the sink first, it is given back instead. The
+    /// sink must not hold any earlier items that haven't been passed on yet.
+    #[inline]
+    pub fn try_pass<S: Sink<T>>(&mut self, item: T, sink: &mut S) -> Result<Option<T>, BufferOverflow> {
+        if let Some(e) = self.overflow {
+            return Err(e);
+        }
+        if self.scanner.exhausted {
+            self.flushed_index += 1;
+            self.scanner.index += 1;
+            return Ok(Some(item));
+        }
+        if self.buffer_in.is_empty() {
+            // Nothing is in progress, so most items can be passed on without buffering them
+            let found = self.scanner.step(&item);
+            if found.is_none() && self.scanner.is_idle() {
+                if self.insert.is_empty() {
+                    self.flushed_index += 1;
+                    return Ok(Some(item));
+                }
+                let position = self.flushed_index;
+                self.insert(position, sink);
+                self.flushed_index += 1;
+                sink.item(item);
+                return Ok(None);
+            }
+            self.buffer_in.push_back(item);
+            self.stepped(found, sink);
+        } else {
+            self.buffer_in.push_back(item);
+        }
+        self.scan(sink);
+        self.limit_buffer(sink)?;
+        Ok(None)
+    }
+
+    /// Pushes several items, skipping straight past those that can't start a
+    /// match if there is a prefilter.
+    pub fn try_push_slice<S: Sink<T>>(&mut self, items: &[T], sink: &mut S) -> Result<(), BufferOverflow> {
+        let mut i = 0;
+        while i < items.len() {
+            let idle = self.scanner.exhausted || (self.buffer_in.is_empty() && self.scanner.is_idle());
+            if idle {
+                let skip = match self.prefilter {
+                    _ if self.scanner.exhausted => items.len() - i,
+                    Some(ref prefilter) if self.insert.is_empty() => prefilter(&items[i ..]),
+                    _ => 0,
+                };
+                sink.items(&items[i .. i + skip]);
+                self.flushed_index += skip;
+                self.scanner.index += skip;
+                i += skip;
+                if i == items.len() {
+                    return Ok(());
+                }
+            }
+            self.try_push(items[i].clone(), sink)?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Called when there are no more items, so that no partial match can
+    /// complete any more. The remaining candidates are reported in order, and
+    /// then everything still buffered is flushed.
+    pub fn finish<S: Sink<T>>(&mut self, sink: &mut S) {
+        loop {
+            self.scan(sink);
+            match self.scanner.candidates.front().cloned() {
+                Some(m) => self.report(m, sink),
+                None => {
+                    let end = self.flushed_index + self.buffer_in.len();
+                    self.flush_to(end, sink);
+                    self.scanner.restart(end);
+                    self.insert(end, sink);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Starts a new stretch of items after `finish`, such as after an error
+    /// from the source, so an empty pattern is inserted at its start again.
+    pub fn resume(&mut self) {
+        self.inserted_at = None;
+    }
+
+    /// Finds all of the matches in a complete sequence of items at once, in
+    /// order, along with the number of matches before each one. Nothing is
+    /// buffered or cloned, but the searcher must not have been used yet.
+    pub fn find_in<F>(&mut self, items: &[T], mut found: F) where
+        F: FnMut(Match, usize) {
+        debug_assert!(self.scanner.index == 0);
+        let scanner = &mut self.scanner;
+        let mut last_end = 0;
+        loop {
+            let mut next = scanner.ready();
+            while next.is_none() && !scanner.exhausted && scanner.index < items.len() {
+                if let Some(ref prefilter) = self.prefilter {
+                    if scanner.is_idle() {
+                        scanner.index += prefilter(&items[scanner.index ..]);
+                        if scanner.index == items.len() {
+                            break;
+                        }
+                    }
+                }
+                next = scanner.step(&items[scanner.index]);
+            }
+            // At the end, nothing can beat the first candidate any more
+            if next.is_none() && !scanner.exhausted {
+                next = scanner.candidates.front().cloned();
+            }
+            let until = next.map_or(items.len() + 1, |m| m.start);
+            scanner.insert_between(&self.insert, last_end .. until, &mut found);
+            match next {
+                Some(m) if !scanner.exhausted => {
+                    found(m, scanner.matches);
+                    scanner.record(m);
+                    last_end = m.end;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // Feeds the buffered items that haven't been scanned yet, which are only
+    // left over when a limit disables a candidate
+    fn scan<S: Sink<T>>(&mut self, sink: &mut S) {
+        while !self.scanner.exhausted && self.scanner.index < self.flushed_index + self.buffer_in.len() {
+            let found = self.scanner.step(&self.buffer_in[self.scanner.index - self.flushed_index]);
+            self.stepped(found, sink);
+        }
+    }
+
+    // Keeps the number of held back items within max_buffer, if there is one
+    fn limit_buffer<S: Sink<T>>(&mut self, sink: &mut S) -> Result<(), BufferOverflow> {
+        let (max_buffer, overflow) = match self.max_buffer {
+            Some(max_buffer) => max_buffer,
+            None => return Ok(()),
+        };
+        while self.buffer_in.len() > max_buffer {
+            match (overflow, self.scanner.candidates.front().cloned()) {
+                (BufferPolicy::Overflow(Overflow::Flush), Some(m)) => self.stepped(Some(m), sink),
+                (BufferPolicy::Overflow(Overflow::Flush), None) => {
+                    // Matches can still start after the oldest item
+                    let next = self.flushed_index + 1;
+                    self.flush_to(next, sink);
+                    self.scanner.rebase(next);
+                    self.stepped(None, sink);
+                }
+                _ => {
+                    let e = BufferOverflow { max_buffer };
+                    self.overflow = Some(e);
+                    return Err(e);
+                }
+            }
+            self.scan(sink);
+        }
+        Ok(())
+    }
+
+    // Reports the matches that nothing can beat, and passes on the items that can't be part of one
+    fn stepped<S: Sink<T>>(&mut self, mut found: Option<Match>, sink: &mut S) {
+        while let Some(m) = found {
+            self.report(m, sink);
+            found = self.scanner.ready();
+        }
+        let settled = self.scanner.settled();
+        self.flush_to(settled, sink);
+    }
+
+    fn report<S: Sink<T>>(&mut self, m: Match, sink: &mut S) {
+        self.flush_to(m.start, sink);
+        if self.scanner.exhausted {
+            // by an insertion before it
+            return;
+        }
+        let len = m.end - m.start;
+        // the matched items are passed on as a slice, so they can't wrap around
+        if self.buffer_in.as_slices().0.len() < len {
+            self.buffer_in.make_contiguous();
+        }
+        sink.matched(m, &self.buffer_in.as_slices().0[.. len], self.scanner.matches);
+        self.buffer_in.drain(0 .. len);
+        self.flushed_index = m.end;
+        self.scanner.record(m);
+        if self.scanner.exhausted {
+            let end = self.flushed_index + self.buffer_in.len();
+            self.flush_to(end, sink);
+        }
+    }
+
+    // Items before this index can't be part of a match
+    fn flush_to<S: Sink<T>>(&mut self, index: usize, sink: &mut S) {
+        if index <= self.flushed_index {
+            return;
+        }
+        if self.insert.is_empty() {
+            for item in self.buffer_in.drain(0 .. index - self.flushed_index) {
+                sink.item(item);
+            }
+            self.flushed_index = index;
+            return;
+        }
+        while self.flushed_index < index {
+            let position = self.flushed_index;
+            self.insert(position, sink);
+            self.flushed_index += 1;
+            if let Some(item) = self.buffer_in.pop_front() {
+                sink.item(item);
+            }
+        }
+        if self.scanner.exhausted && !self.buffer_in.is_empty() {
+            // An insertion reached a limit, so nothing else is held back
+            let end = self.flushed_index + self.buffer_in.len();
+            for item in self.buffer_in.drain(..) {
+                sink.item(item);
+            }
+            self.flushed_index = end;
+            self.scanner.restart(end);
+        }
+    }
+
+    // Reports an empty match at this position, if an empty pattern is enabled
+    fn insert<S: Sink<T>>(&mut self, position: usize, sink: &mut S) {
+        if self.inserted_at == Some(position) {
+            return;
+        }
+        if let Some(pattern) = self.scanner.insertion(&self.insert) {
+            self.inserted_at = Some(position);
+            let m = Match { pattern_index: pattern, start: position, end: position };
+            sink.matched(m, &[], self.scanner.matches);
+            self.scanner.count(pattern);
+        }
+    }
+}
+
+impl <'a, T: PartialEq + Clone> Scanner<'a, T> {
+
+    // Feeds the item at self.index to the matcher, returning a match once nothing can beat it
+    #[inline]
+    fn step(&mut self, item: &T) -> Option<Match> {
+        self.depth = self.matcher.next(&mut self.state, item, self.eq.as_deref());
+        self.index += 1;
+
+        // The usual case, where this item doesn't continue or complete anything
+        if self.depth == 0 && self.candidates.is_empty() {
+            return None;
+        }
+        if self.depth > 0 {
+            self.add_candidates();
+        }
+        self.ready()
+    }
+
+    // Adds the matches ending at the current item that are better than the
+    // candidates, or that start after all of them
+    fn add_candidates(&mut self) {
+        let index = self.index;
+        let matcher = &self.matcher;
+        let candidates = &mut self.candidates;
+        let pattern_matches = &self.pattern_matches;
+        let pattern_limits = &self.pattern_limits;
+        let enabled = |p: usize| pattern_limits[p].is_none_or(|l| pattern_matches[p] < l);
+        // Shorter matches start further right, so they are tried until one is added
+        matcher.each_match(&self.state, enabled, |pattern, len| {
+            let m = Match { pattern_index: pattern, start: index - len, end: index };
+            // the candidates end in increasing order, and each starts after the one before it ends
+            let level = candidates.partition_point(|c| c.end <= m.start);
+            if let Some(&c) = candidates.get(level) {
+                let better = m.start < c.start || (m.start == c.start
+                    && matcher.ranking().prefers(pattern, len, c.pattern_index, c.end - c.start));
+                if !better {
+                    return true;
+                }
+            }
+            // the candidates after it would have to start after this item, so there are none yet
+            candidates.truncate(level);
+            candidates.push_back(m);
+            false
+        });
+    }
+
+    // The first candidate, if nothing can beat it any more
+    fn ready(&self) -> Option<Match> {
+        if self.exhausted {
+            return None;
+        }
+        // the start of the longest partial match that is still alive
+        let live_start = self.index - self.depth;
+        match self.candidates.front() {
+            Some(&m) if live_start > m.start
+                    || (live_start == m.start && !self.matcher.can_improve(&self.state, m.pattern_index)) => Some(m),
+            _ => None,
+        }
+    }
+
+    // Whether there is no partial or complete match in progress
+    #[inline]
+    fn is_idle(&self) -> bool {
+        self.depth == 0 && self.candidates.is_empty()
+    }
+
+    // The index before which no item can be part of a match
+    fn settled(&self) -> usize {
+        let live_start = self.index - self.depth;
+        self.candidates.front().map_or(live_start, |m| m.start.min(live_start))
+    }
+
+    fn enabled(&self, pattern: usize) -> bool {
+        self.pattern_limits[pattern].is_none_or(|l| self.pattern_matches[pattern] < l)
+    }
+
+    // The empty pattern that matches next, if any
+    fn insertion(&self, insert: &[usize]) -> Option<usize> {
+        if self.exhausted {
+            return None;
+        }
+        insert.iter().cloned().find(|&p| self.enabled(p))
+    }
+
+    fn insert_between<F>(&mut self, insert: &[usize], positions: Range<usize>, found: &mut F) where
+        F: FnMut(Match, usize) {
+        for position in positions {
+            match self.insertion(insert) {
+                Some(pattern) => {
+                    found(Match { pattern_index: pattern, start: position, end: position }, self.matches);
+                    self.count(pattern);
+                }
+                None => return,
+            }
+        }
+    }
+
+    // Forgets everything in progress, and scans again from this index
+    fn restart(&mut self, index: usize) {
+        self.index = index;
+        self.matcher.reset(&mut self.state);
+        self.depth = 0;
+        self.candidates.clear();
+    }
+
+    // Forgets the partial matches that start before this index, without scanning anything again
+    fn rebase(&mut self, index: usize) {
+        self.matcher.rebase(&mut self.state, self.index - index);
+        self.depth = self.matcher.depth(&self.state);
+    }
+
+    // Matching continues after the first candidate, from the candidates after
+    // it. They were chosen while its pattern was still enabled, so if one of
+    // them has the same pattern and that has now reached its limit, the items
+    // after it are scanned again instead.
+    fn record(&mut self, m: Match) {
+        debug_assert!(self.candidates.front() == Some(&m));
+        self.candidates.pop_front();
+        self.count(m.pattern_index);
+        let disabled = !self.enabled(m.pattern_index)
+            && self.candidates.iter().any(|c| c.pattern_index == m.pattern_index);
+        if self.exhausted || disabled {
+            self.restart(m.end);
+        } else {
+            self.rebase(m.end);
+        }
+    }
+
+    fn count(&mut self, pattern: usize) {
+        self.matches += 1;
+        self.pattern_matches[pattern] += 1;
+        let pattern_matches = &self.pattern_matches;
+        self.exhausted = self.limit.is_some_and(|l| self.matches >= l)
+            || self.pattern_limits.iter().enumerate().all(|(p, l)| l.is_some_and(|l| pattern_matches[p] >= l));
+    }
+}
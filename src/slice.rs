@@ -0,0 +1,293 @@
+//! Replacement in sequences that are already in memory.
+//!
+//! The whole slice is searched before any output is built, so unmatched items
+//! are copied in runs, or not at all when nothing matches. The byte functions
+//! also skip quickly over the bytes that can't start a match.
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use {Match, ReplaceBuilder, Replacement, Replacer, Sharing, Substitutes};
+use prefilter::byte_searcher;
+use searcher::Searcher;
+
+/// Replaces the patterns in a slice, like `replace_all` does for an iterator.
+/// For bytes, `ReplaceBuilder::replace_bytes` is faster.
+pub fn replace_in_slice<'a, T>(items: &[T], replacements: Vec<Replacement<'a, T>>) -> Vec<T> where
+    T: 'a + PartialEq + Clone {
+    ReplaceBuilder::new(replacements).replace_slice(items)
+}
+
+/// Like `replace_in_slice`, but the slice is borrowed if nothing matches.
+pub fn replace_in_slice_cow<'a, 's, T>(items: &'s [T], replacements: Vec<Replacement<'a, T>>) -> Cow<'s, [T]> where
+    T: 'a + PartialEq + Clone {
+    ReplaceBuilder::new(replacements).replace_slice_cow(items)
+}
+
+/// Replacement in a `Vec`, without a new allocation if it can be avoided.
+pub trait ReplaceVec<'a, T> where
+    T: 'a + Clone {
+
+    fn replace_in_place(&mut self, replacements: Vec<Replacement<'a, T>>);
+}
+
+impl <'a, T> ReplaceVec<'a, T> for Vec<T> where
+    T: 'a + PartialEq + Clone {
+
+    fn replace_in_place(&mut self, replacements: Vec<Replacement<'a, T>>) {
+        ReplaceBuilder::new(replacements).replace_in_place(self)
+    }
+}
+
+// The matches in a slice, and what they are replaced with
+struct Substitutions<T> {
+    // each match, with the number of substituted items for it
+    matches: Vec<(Match, usize)>,
+    items: VecDeque<T>,
+}
+
+impl <T> Substitutions<T> {
+    fn len_after(&self, len: usize) -> usize {
+        let matched: usize = self.matches.iter().map(|&(m, _)| m.end - m.start).sum();
+        len - matched + self.items.len()
+    }
+
+    // Whether the output never gets ahead of the input, so it can be written over it
+    fn fits_in_place(&self) -> bool {
+        let mut read = 0;
+        let mut written = 0;
+        self.matches.iter().all(|&(m, n)| {
+            written += m.start - read + n;
+            read = m.end;
+            written <= read
+        })
+    }
+}
+
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    /// Replaces the patterns in a slice. The output is the same as collecting
+    /// `apply(items.iter().cloned())`.
+    pub fn replace_slice(self, items: &[T]) -> Vec<T> {
+        self.compile().replace_slice(items)
+    }
+
+    /// Like `replace_slice`, but the slice is borrowed if nothing matches.
+    pub fn replace_slice_cow<'s>(self, items: &'s [T]) -> Cow<'s, [T]> {
+        self.compile().replace_slice_cow(items)
+    }
+
+    /// Replaces the patterns in a `Vec`. Unless a replacement is longer than
+    /// its match and the output would overtake the input, the items are moved
+    /// within the existing allocation.
+    pub fn replace_in_place(self, items: &mut Vec<T>) {
+        self.compile().replace_in_place(items)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> ReplaceBuilder <'a, u8, S> {
+    /// Like `replace_slice`, but skips quickly over bytes that can't start a match, and
+    /// compiles the patterns for bytes as `ordered` describes.
+    pub fn replace_bytes(self, bytes: &[u8]) -> Vec<u8> {
+        self.compile_bytes().replace_bytes(bytes)
+    }
+
+    /// Like `replace_slice_cow`, but skips quickly over bytes that can't start a match, and
+    /// compiles the patterns for bytes as `ordered` describes.
+    pub fn replace_bytes_cow<'s>(self, bytes: &'s [u8]) -> Cow<'s, [u8]> {
+        self.compile_bytes().replace_bytes_cow(bytes)
+    }
+
+    /// Like `replace_in_place`, but skips quickly over bytes that can't start a match, and
+    /// compiles the patterns for bytes as `ordered` describes.
+    pub fn replace_bytes_in_place(self, bytes: &mut Vec<u8>) {
+        self.compile_bytes().replace_bytes_in_place(bytes)
+    }
+}
+
+impl <'a, T, S> Replacer <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    pub fn replace_slice(&self, items: &[T]) -> Vec<T> {
+        let (searcher, replace_with) = self.searcher();
+        build(items, substitutions(searcher, &*replace_with, items))
+    }
+
+    pub fn replace_slice_cow<'s>(&self, items: &'s [T]) -> Cow<'s, [T]> {
+        let (searcher, replace_with) = self.searcher();
+        build_cow(items, substitutions(searcher, &*replace_with, items))
+    }
+
+    pub fn replace_in_place(&self, items: &mut Vec<T>) {
+        let (searcher, replace_with) = self.searcher();
+        let subs = substitutions(searcher, &*replace_with, items);
+        replace_in_place(items, subs);
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> Replacer <'a, u8, S> {
+    /// Like `replace_slice`, but skips quickly over bytes that can't start a match.
+    pub fn replace_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        let (searcher, replace_with) = byte_searcher(self);
+        build(bytes, substitutions(searcher, &*replace_with, bytes))
+    }
+
+    /// Like `replace_slice_cow`, but skips quickly over bytes that can't start a match.
+    pub fn replace_bytes_cow<'s>(&self, bytes: &'s [u8]) -> Cow<'s, [u8]> {
+        let (searcher, replace_with) = byte_searcher(self);
+        build_cow(bytes, substitutions(searcher, &*replace_with, bytes))
+    }
+
+    /// Like `replace_in_place`, but skips quickly over bytes that can't start a match.
+    pub fn replace_bytes_in_place(&self, bytes: &mut Vec<u8>) {
+        let (searcher, replace_with) = byte_searcher(self);
+        let subs = substitutions(searcher, &*replace_with, bytes);
+        replace_in_place(bytes, subs);
+    }
+}
+
+fn substitutions<'a, T>(mut searcher: Searcher<'a, T>, replace_with: &dyn Substitutes<T>, items: &[T]) -> Substitutions<T> where
+    T: PartialEq + Clone {
+    let mut subs = Substitutions { matches: Vec::new(), items: VecDeque::new() };
+    searcher.find_in(items, |m, match_index| {
+        let before = subs.items.len();
+        replace_with.write(m.pattern_index, &items[m.start .. m.end], match_index, &mut subs.items);
+        subs.matches.push((m, subs.items.len() - before));
+    });
+    subs
+}
+
+fn replace_in_place<T: Clone>(items: &mut Vec<T>, mut subs: Substitutions<T>) {
+    if subs.matches.is_empty() {
+        return;
+    }
+    if !subs.fits_in_place() {
+        *items = build(items, subs);
+        return;
+    }
+    let mut write = 0;
+    let mut read = 0;
+    for &(m, n) in &subs.matches {
+        while read < m.start {
+            items.swap(write, read);
+            write += 1;
+            read += 1;
+        }
+        for item in subs.items.drain(.. n) {
+            items[write] = item;
+            write += 1;
+        }
+        read = m.end;
+    }
+    while read < items.len() {
+        items.swap(write, read);
+        write += 1;
+        read += 1;
+    }
+    items.truncate(write);
+}
+
+fn build_cow<'s, T: Clone>(items: &'s [T], subs: Substitutions<T>) -> Cow<'s, [T]> {
+    if subs.matches.is_empty() {
+        Cow::Borrowed(items)
+    } else {
+        Cow::Owned(build(items, subs))
+    }
+}
+
+fn build<T: Clone>(items: &[T], mut subs: Substitutions<T>) -> Vec<T> {
+    let mut out = Vec::with_capacity(subs.len_after(items.len()));
+    let mut read = 0;
+    for &(m, n) in &subs.matches {
+        out.extend_from_slice(&items[read .. m.start]);
+        out.extend(subs.items.drain(.. n));
+        read = m.end;
+    }
+    out.extend_from_slice(&items[read ..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use MatchKind;
+
+    #[test]
+    pub fn test_replace_in_slice_agrees_with_replace_all(){
+        let reps = || vec![Replacement::new(b"abc", b"X"),
+                             Replacement::new(b"bcd", b"YYYYY"),
+                             Replacement::new(b"e", b"")];
+        let inputs: [&[u8]; 5] = [b"", b"abcd", b"xbcdabce", b"eeabcbcdeab", b"zzz"];
+        for &kind in &[MatchKind::LeftmostFirst, MatchKind::LeftmostLongest] {
+            for input in inputs.iter() {
+                let expected: Vec<u8> = ReplaceBuilder::new(reps()).match_kind(kind)
+                    .apply(input.iter().cloned()).collect();
+                assert_eq!(ReplaceBuilder::new(reps()).match_kind(kind).replace_slice(input), expected);
+                assert_eq!(&*ReplaceBuilder::new(reps()).match_kind(kind).replace_slice_cow(input), &expected[..]);
+
+                let mut v = input.to_vec();
+                ReplaceBuilder::new(reps()).match_kind(kind).replace_in_place(&mut v);
+                assert_eq!(v, expected);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_replace_in_slice_cow_borrows_without_matches(){
+        let reps = || vec![Replacement::new(b"abc", b"X"), Replacement::new(b"e", b"")];
+        let input = b"a bc, no match";
+        assert!(matches!(replace_in_slice_cow(input, reps()), Cow::Borrowed(_)));
+        assert!(matches!(replace_in_slice_cow(b"a bcde", reps()), Cow::Owned(_)));
+    }
+
+    #[test]
+    pub fn test_replace_in_place_keeps_allocation(){
+        let reps = || vec![Replacement::new(b"abc", b"X"),
+                             Replacement::new(b"bcd", b"YYYYY"),
+                             Replacement::new(b"e", b"")];
+        let mut v = b"abcxabcxe".to_vec();
+        let ptr = v.as_ptr();
+        v.replace_in_place(reps());
+        assert_eq!(v.as_slice(), b"XxXx");
+        assert_eq!(v.as_ptr(), ptr);
+
+        // the first replacement overtakes the input, so it can't be done in place
+        let mut v = b"bcdabc".to_vec();
+        v.replace_in_place(reps());
+        assert_eq!(v.as_slice(), b"YYYYYX");
+    }
+
+    #[test]
+    pub fn test_replace_in_place_moves_non_copy_items(){
+        let items: Vec<Rc<u8>> = b"xaby".iter().map(|&b| Rc::new(b)).collect();
+        let search = [Rc::new(b'a'), Rc::new(b'b')];
+        let mut v = items.clone();
+        v.replace_in_place(vec![Replacement::new(&search, &[])]);
+        assert!(Rc::ptr_eq(&v[0], &items[0]));
+        assert!(Rc::ptr_eq(&v[1], &items[3]));
+        // the unmatched items were moved, not cloned
+        assert_eq!(Rc::strong_count(&items[3]), 2);
+    }
+
+    #[test]
+    pub fn test_replace_in_slice_with_limit(){
+        let reps = vec![Replacement::new(b"abc", b"X"), Replacement::new(b"e", b"")];
+        let v = ReplaceBuilder::new(reps).limit(2).replace_slice(b"eabceabc");
+        assert_eq!(v.as_slice(), b"Xeabc");
+        let v = replace_in_slice(b"eabceabc", vec![Replacement::new(b"abc", b"X").with_limit(1)]);
+        assert_eq!(v.as_slice(), b"eXeabc");
+    }
+
+    #[test]
+    pub fn test_replace_in_slice_of_borrowed_items(){
+        let text = String::from("a b a");
+        let mut words: Vec<&str> = text.split(' ').collect();
+        assert_eq!(replace_in_slice(&words, vec![Replacement::new(&["a", "b"], &["c"])]), vec!["c", "a"]);
+        words.replace_in_place(vec![Replacement::new(&["b"], &[])]);
+        assert_eq!(words, vec!["a", "a"]);
+    }
+}
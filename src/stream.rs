@@ -0,0 +1,276 @@
+//! Adapters for asynchronous streams of items and of bytes, enabled by the
+//! `stream` feature.
+//!
+//! The inner stream or reader must be `Unpin`. Others can be used by pinning
+//! them with `Box::pin` first.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+use {CheckedBuilder, CheckedReplacer, ReplaceBuilder, Replacement, Replacer, ReplaceSink, Sharing, Substitutes};
+use prefilter::byte_searcher;
+use searcher::Searcher;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// A stream adapter that replaces occurrences of patterns with other items.
+pub struct ReplaceStream <'a, S, T: 'a + Clone> {
+    inner: S,
+    searcher: Searcher<'a, T>,
+    replace_with: Arc<dyn Substitutes<T> + 'a>,
+    buffer_out: VecDeque<T>,
+    done: bool,
+}
+
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    /// Replaces the patterns in the items of a stream.
+    pub fn stream<St>(self, inner: St) -> ReplaceStream<'a, St, T> where
+        St: Stream<Item = T> + Unpin {
+        self.compile().stream(inner)
+    }
+}
+
+impl <'a, T, S> Replacer <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    pub fn stream<St>(&self, inner: St) -> ReplaceStream<'a, St, T> where
+        St: Stream<Item = T> + Unpin {
+        let (searcher, replace_with) = self.searcher();
+        ReplaceStream {
+            inner,
+            searcher,
+            replace_with,
+            buffer_out: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl <'a, S, T> Stream for ReplaceStream <'a, S, T> where
+    S: Stream<Item = T> + Unpin,
+    T: PartialEq + Clone + Unpin {
+
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.buffer_out.pop_front() {
+                return Poll::Ready(Some(item));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            let mut sink = ReplaceSink {
+                replace_with: &*this.replace_with,
+                buffer_out: &mut this.buffer_out,
+            };
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.searcher.try_push(item, &mut sink).is_err() {
+                        this.done = true;
+                    }
+                }
+                Poll::Ready(None) => {
+                    this.searcher.finish(&mut sink);
+                    this.done = true;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+pub trait ReplaceStreamExt<'a, T>: Stream<Item = T> + Unpin + Sized where
+    T: 'a + PartialEq + Clone {
+
+    fn replace(self, search_for: &'a [T], replace_with: &'a [T]) -> ReplaceStream<'a, Self, T> {
+        ReplaceBuilder::new(vec![Replacement::new(search_for, replace_with)]).stream(self)
+    }
+
+    fn replace_all(self, replacements: Vec<Replacement<'a, T>>) -> ReplaceStream<'a, Self, T> {
+        ReplaceBuilder::new(replacements).stream(self)
+    }
+}
+
+impl <'a, S, T> ReplaceStreamExt<'a, T> for S where
+    S: Stream<Item = T> + Unpin,
+    T: 'a + PartialEq + Clone {}
+
+/// An asynchronous reader that replaces patterns in the bytes read from
+/// another reader.
+pub struct ReplaceAsyncRead <'a, R> {
+    inner: R,
+    searcher: Searcher<'a, u8>,
+    replace_with: Arc<dyn Substitutes<u8> + 'a>,
+    buffer_out: VecDeque<u8>,
+    chunk: Vec<u8>,
+    done: bool,
+}
+
+impl <'a, S: Sharing<'a, u8>> ReplaceBuilder <'a, u8, S> {
+    /// Replaces the patterns in the bytes read from an asynchronous reader.
+    pub fn async_reader<R>(self, inner: R) -> ReplaceAsyncRead<'a, R> where
+        R: AsyncRead + Unpin {
+        self.compile_bytes().async_reader(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> CheckedBuilder <'a, u8, S> {
+    /// Like `ReplaceBuilder::async_reader`, but a `BufferOverflow` is returned
+    /// as an `io::Error` of kind `Other`.
+    pub fn async_reader<R>(self, inner: R) -> ReplaceAsyncRead<'a, R> where
+        R: AsyncRead + Unpin {
+        self.builder.async_reader(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> CheckedReplacer <'a, u8, S> {
+    pub fn async_reader<R>(&self, inner: R) -> ReplaceAsyncRead<'a, R> where
+        R: AsyncRead + Unpin {
+        self.replacer.async_reader(inner)
+    }
+}
+
+impl <'a, S: Sharing<'a, u8>> Replacer <'a, u8, S> {
+    pub fn async_reader<R>(&self, inner: R) -> ReplaceAsyncRead<'a, R> where
+        R: AsyncRead + Unpin {
+        let (searcher, replace_with) = byte_searcher(self);
+        ReplaceAsyncRead {
+            inner,
+            searcher,
+            replace_with,
+            buffer_out: VecDeque::new(),
+            chunk: vec![0; CHUNK_SIZE],
+            done: false,
+        }
+    }
+}
+
+impl <'a, R: AsyncRead + Unpin> ReplaceAsyncRead <'a, R> {
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Reads until there is some output, or the inner reader is finished
+    fn poll_fill(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let Some(e) = self.searcher.overflow() {
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        while self.buffer_out.is_empty() && !self.done {
+            let mut read_buf = ReadBuf::new(&mut self.chunk);
+            match Pin::new(&mut self.inner).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = read_buf.filled().len();
+            let mut sink = ReplaceSink {
+                replace_with: &*self.replace_with,
+                buffer_out: &mut self.buffer_out,
+            };
+            if n == 0 {
+                self.searcher.finish(&mut sink);
+                self.done = true;
+            }
+            self.searcher.try_push_slice(&self.chunk[.. n], &mut sink).map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl <'a, R: AsyncRead + Unpin> AsyncRead for ReplaceAsyncRead <'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_fill(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        while buf.remaining() > 0 && !this.buffer_out.is_empty() {
+            let n = {
+                let front = this.buffer_out.as_slices().0;
+                let n = front.len().min(buf.remaining());
+                buf.put_slice(&front[.. n]);
+                n
+            };
+            this.buffer_out.drain(.. n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl <'a, R: AsyncRead + Unpin> AsyncBufRead for ReplaceAsyncRead <'a, R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match this.poll_fill(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.buffer_out.as_slices().0)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().buffer_out.drain(.. amt);
+    }
+}
+
+/// Also works for an `AsyncBufRead`, whose buffer is then copied in chunks.
+pub trait ReplaceAsyncReadExt<'a>: AsyncRead + Unpin + Sized {
+
+    fn replace(self, search_for: &'a [u8], replace_with: &'a [u8]) -> ReplaceAsyncRead<'a, Self> {
+        ReplaceBuilder::new(vec![Replacement::new(search_for, replace_with)]).async_reader(self)
+    }
+
+    fn replace_all(self, replacements: Vec<Replacement<'a, u8>>) -> ReplaceAsyncRead<'a, Self> {
+        ReplaceBuilder::new(replacements).async_reader(self)
+    }
+}
+
+impl <'a, R: AsyncRead + Unpin> ReplaceAsyncReadExt<'a> for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use futures::executor::block_on;
+    use futures::stream::{self, StreamExt};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    #[test]
+    pub fn test_replace_stream() {
+        let reps = vec![Replacement::new(&[4, 5], &[100]),
+                        Replacement::new(&[5, 6, 7], &[200])];
+        let v: Vec<u32> = block_on(stream::iter(vec![3, 4, 5, 6, 5, 6, 7, 5, 6])
+            .replace_all(reps)
+            .collect());
+        assert_eq!(v, vec![3, 100, 6, 200, 5, 6]);
+    }
+
+    #[test]
+    pub fn test_replace_async_read_with_duplex() {
+        let input = b"a needle, needs a needl and a needle".to_vec();
+        // a tiny pipe, so that the matches span several reads
+        let (mut client, server) = duplex(3);
+        let writer = thread::spawn(move || {
+            block_on(client.write_all(&input)).unwrap();
+        });
+
+        let reps = vec![Replacement::new(b"needle", b"NEEDLE"),
+                        Replacement::new(b"need", b"?")];
+        let mut output = Vec::new();
+        block_on(BufReader::new(server).replace_all(reps).read_to_end(&mut output)).unwrap();
+        writer.join().unwrap();
+        assert_eq!(output.as_slice(), &b"a NEEDLE, ?s a ?l and a NEEDLE"[..]);
+    }
+}
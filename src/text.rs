@@ -0,0 +1,67 @@
+//! Replacement in text.
+//!
+//! Patterns are matched byte by byte in UTF-8. A pattern that is itself valid
+//! UTF-8 can only match a whole sequence of chars, so matches always start and
+//! end on char boundaries and the output is valid UTF-8 too.
+
+use std::str::Chars;
+
+use {Replace, ReplaceBuilder, Replacement};
+
+/// Replaces each `(search_for, replace_with)` pair in one pass, like calling
+/// `str::replace` for all of them at once. Where matches overlap, the leftmost
+/// wins, and then the pair declared first.
+///
+/// Empty patterns never match.
+pub fn replace_all_str(text: &str, replacements: &[(&str, &str)]) -> String {
+    let replacements = replacements.iter()
+        .map(|&(search_for, replace_with)| Replacement::new(search_for.as_bytes(), replace_with.as_bytes()))
+        .collect();
+    let bytes = ReplaceBuilder::new(replacements).replace_bytes(text.as_bytes());
+    String::from_utf8(bytes).expect("replacing valid UTF-8 with valid UTF-8 should be valid UTF-8")
+}
+
+/// Like `replace_all_str`, but over a stream of chars, so the text doesn't need
+/// to be in memory all at once. Collect it into a `String` with `collect()`.
+pub fn replace_all_chars<I>(chars: I, replacements: &[(&str, &str)]) -> Replace<'static, I::IntoIter, char> where
+    I: IntoIterator<Item = char> {
+    let replacements = replacements.iter()
+        .map(|&(search_for, replace_with)| Replacement::owned(search_for.chars().collect(), replace_with.chars().collect()))
+        .collect();
+    ReplaceBuilder::new(replacements).apply(chars)
+}
+
+/// The `Replace` adapter over the chars of a `str`.
+pub type ReplaceChars<'s> = Replace<'static, Chars<'s>, char>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAIRS: [(&str, &str); 4] = [("café", "coffee"), ("é", "e"), ("naïve", "日本"), ("日本", "Japan")];
+
+    #[test]
+    pub fn test_replace_all_str(){
+        let text = "a naïve café, or an é in 日本";
+        assert_eq!(replace_all_str(text, &PAIRS), "a 日本 coffee, or an e in Japan");
+        let chars: ReplaceChars = replace_all_chars(text.chars(), &PAIRS);
+        assert_eq!(chars.collect::<String>(), "a 日本 coffee, or an e in Japan");
+    }
+
+    #[test]
+    pub fn test_replace_all_str_declared_order(){
+        let pairs = [("ab", "1"), ("abc", "2"), ("bc", "3")];
+        assert_eq!(replace_all_str("abcbc", &pairs), "1c3");
+        assert_eq!(replace_all_chars("abcbc".chars(), &pairs).collect::<String>(), "1c3");
+    }
+
+    #[test]
+    pub fn test_replace_all_str_only_whole_chars(){
+        // "é" is C3 A9, and "é" spelled with a combining accent is 65 CC 81
+        let text = "Ã© e\u{301} é";
+        assert_eq!(replace_all_str(text, &[("é", "E"), ("\u{301}", "'")]), "Ã© e' E");
+        // "©" is C2 A9, which shares its last byte with "é"
+        assert_eq!(replace_all_str("é©", &[("©", "(c)")]), "é(c)");
+        assert_eq!(replace_all_str("", &[("", "x")]), "");
+    }
+}
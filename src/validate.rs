@@ -0,0 +1,221 @@
+//! Checking a set of replacements before compiling it.
+//!
+//! Nothing here is needed for the replacements to run. The adapters on
+//! `ReplaceBuilder` compile whatever they are given, and then an empty pattern
+//! never matches and a shadowed one is never chosen.
+
+use std::error::Error;
+use std::fmt;
+
+use {MatchKind, ReplaceBuilder, Replacer, Sharing};
+use matcher::{Pattern, Ranking};
+
+/// A problem with a set of replacements, found by `ReplaceBuilder::build`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The pattern is empty, and `allow_empty_patterns` wasn't set.
+    EmptyPattern { pattern_index: usize },
+    /// The pattern can never match, because wherever it could, the pattern
+    /// `shadowed_by` matches at the same start and is preferred.
+    ShadowedPattern { pattern_index: usize, shadowed_by: usize },
+    /// A limit of zero, which disables a pattern, or with `None`, all of them.
+    ZeroLimit { pattern_index: Option<usize> },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::EmptyPattern { pattern_index } => write!(f, "pattern {} is empty", pattern_index),
+            BuildError::ShadowedPattern { pattern_index, shadowed_by } => {
+                write!(f, "pattern {} can never match, because pattern {} is always preferred", pattern_index, shadowed_by)
+            }
+            BuildError::ZeroLimit { pattern_index: Some(pattern_index) } => {
+                write!(f, "pattern {} has a limit of zero", pattern_index)
+            }
+            BuildError::ZeroLimit { pattern_index: None } => write!(f, "the limit is zero"),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+impl <'a, T, S> ReplaceBuilder <'a, T, S> where
+    T: 'a + PartialEq + Clone,
+    S: Sharing<'a, T> {
+
+    /// Compiles the replacements, or returns the first of their `problems`.
+    pub fn build(self) -> Result<Replacer<'a, T, S>, BuildError> {
+        match self.problems().into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(self.compile()),
+        }
+    }
+
+    /// Everything that `build` would reject, in the order of the patterns.
+    ///
+    /// Shadowing is only detected between literal patterns, and not at all
+    /// with `eq_by`.
+    pub fn problems(&self) -> Vec<BuildError> {
+        let mut problems = Vec::new();
+        if self.limit == Some(0) {
+            problems.push(BuildError::ZeroLimit { pattern_index: None });
+        }
+        let priorities: Vec<_> = self.replacements.iter().map(|r| r.priority).collect();
+        let ranking = Ranking::new(self.match_kind, &priorities);
+        for (i, replacement) in self.replacements.iter().enumerate() {
+            if replacement.search_for.len() == 0 && !self.allow_empty {
+                problems.push(BuildError::EmptyPattern { pattern_index: i });
+            }
+            if replacement.limit == Some(0) {
+                problems.push(BuildError::ZeroLimit { pattern_index: Some(i) });
+            }
+            if self.eq.is_none() {
+                if let Some(by) = self.shadowed_by(i, &ranking) {
+                    problems.push(BuildError::ShadowedPattern { pattern_index: i, shadowed_by: by });
+                }
+            }
+        }
+        problems
+    }
+
+    // The best ranked pattern that is always preferred over this one where it matches
+    fn shadowed_by(&self, pattern: usize, ranking: &Ranking) -> Option<usize> {
+        let q = match self.replacements[pattern].search_for {
+            Pattern::Literal(ref items) => items,
+            Pattern::Elements(_) => return None,
+        };
+        (0 .. self.replacements.len())
+            .filter(|&p| ranking.rank(p) < ranking.rank(pattern))
+            .filter(|&p| {
+                // a limited pattern stops shadowing once it runs out
+                let replacement = &self.replacements[p];
+                let items = match replacement.search_for {
+                    Pattern::Literal(ref items) if replacement.limit.is_none() => items,
+                    _ => return false,
+                };
+                // an empty pattern is only inserted where nothing else matches
+                let found = match self.match_kind {
+                    MatchKind::LeftmostLongest => items == q,
+                    _ => q.starts_with(items),
+                };
+                found && (!items.is_empty() || q.is_empty())
+            })
+            .min_by_key(|&p| ranking.rank(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Replacement;
+
+    fn builder<'a>(replacements: &[(&'a str, &'a str)]) -> ReplaceBuilder<'a, u8> {
+        ReplaceBuilder::new(replacements.iter()
+            .map(|&(s, r)| Replacement::new(s.as_bytes(), r.as_bytes()))
+            .collect())
+    }
+
+    #[test]
+    pub fn test_build(){
+        let replacer = builder(&[("ab", "x"), ("b", "y")]).build().unwrap();
+        assert_eq!(replacer.replace_bytes(b"abb"), b"xy");
+    }
+
+    #[test]
+    pub fn test_build_rejects_empty_pattern(){
+        let result = builder(&[("a", "x"), ("", "y")]).build();
+        assert_eq!(result.err(), Some(BuildError::EmptyPattern { pattern_index: 1 }));
+    }
+
+    #[test]
+    pub fn test_shadowed_patterns(){
+        let problems = builder(&[("ab", "1"), ("abc", "2"), ("ab", "3"), ("b", "4")]).problems();
+        assert_eq!(problems, vec![
+            BuildError::ShadowedPattern { pattern_index: 1, shadowed_by: 0 },
+            BuildError::ShadowedPattern { pattern_index: 2, shadowed_by: 0 },
+        ]);
+        let problems = builder(&[("ab", "1"), ("abc", "2"), ("ab", "3")])
+            .match_kind(MatchKind::LeftmostLongest)
+            .problems();
+        assert_eq!(problems, vec![BuildError::ShadowedPattern { pattern_index: 2, shadowed_by: 0 }]);
+    }
+
+    #[test]
+    pub fn test_shadowed_by_priority(){
+        let replacements = vec![
+            Replacement::new(b"abc", b"1"),
+            Replacement::new(b"ab", b"2").with_priority(1),
+            Replacement::new(b"ab", b"3").with_priority(1).with_limit(1),
+        ];
+        let problems = ReplaceBuilder::new(replacements).match_kind(MatchKind::Priority).problems();
+        assert_eq!(problems, vec![
+            BuildError::ShadowedPattern { pattern_index: 0, shadowed_by: 1 },
+            BuildError::ShadowedPattern { pattern_index: 2, shadowed_by: 1 },
+        ]);
+    }
+
+    #[test]
+    pub fn test_limited_pattern_does_not_shadow(){
+        let replacements = vec![Replacement::new(b"a", b"1").with_limit(1), Replacement::new(b"a", b"2")];
+        let builder = ReplaceBuilder::new(replacements);
+        assert_eq!(builder.problems(), vec![]);
+        assert_eq!(builder.build().unwrap().replace_bytes(b"aa"), b"12");
+    }
+
+    #[test]
+    pub fn test_zero_limits(){
+        let replacements = vec![Replacement::new(b"a", b"1").with_limit(0)];
+        let problems = ReplaceBuilder::new(replacements).limit(0).problems();
+        assert_eq!(problems, vec![
+            BuildError::ZeroLimit { pattern_index: None },
+            BuildError::ZeroLimit { pattern_index: Some(0) },
+        ]);
+    }
+
+    #[test]
+    pub fn test_insert_empty_pattern(){
+        let replace = |replacements: &[(&str, &str)], text: &str| {
+            let bytes = builder(replacements).allow_empty_patterns().build().unwrap().replace_bytes(text.as_bytes());
+            String::from_utf8(bytes).unwrap()
+        };
+        assert_eq!(replace(&[("", "-")], "abc"), "abc".replace("", "-"));
+        assert_eq!(replace(&[("", "-")], ""), "".replace("", "-"));
+        assert_eq!(replace(&[("b", "B"), ("", "-")], "abc"), "-aB-c-");
+        assert_eq!(replace(&[("bc", "X"), ("", "-")], "abcd"), "-aX-d-");
+    }
+
+    #[test]
+    pub fn test_insert_empty_pattern_agrees_across_adapters(){
+        let cases: &[(&[(&str, &str)], &str)] = &[
+            (&[("", "-")], "abc"),
+            (&[("b", "B"), ("", "-")], "abcb"),
+            (&[("abcd", "X"), ("", "-")], "abcabcd"),
+            (&[("ab", "X"), ("bc", "Y"), ("", "-")], "abcbc"),
+        ];
+        for &(replacements, text) in cases {
+            let expected = builder(replacements).allow_empty_patterns().replace_slice(text.as_bytes());
+            let iterated: Vec<u8> = builder(replacements).allow_empty_patterns().apply(text.bytes()).collect();
+            let mut in_place = text.as_bytes().to_vec();
+            builder(replacements).allow_empty_patterns().replace_bytes_in_place(&mut in_place);
+            assert_eq!(iterated, expected, "{:?} in {:?}", replacements, text);
+            assert_eq!(in_place, expected, "{:?} in {:?}", replacements, text);
+        }
+    }
+
+    #[test]
+    pub fn test_insert_empty_pattern_with_limits(){
+        let replacements = || vec![Replacement::new(b"b", b"B"), Replacement::new(b"", b"-")];
+        let sliced = ReplaceBuilder::new(replacements()).allow_empty_patterns().limit(3).replace_slice(b"abcbc");
+        let iterated: Vec<u8> = ReplaceBuilder::new(replacements()).allow_empty_patterns().limit(3)
+            .apply(b"abcbc".iter().cloned())
+            .collect();
+        assert_eq!(sliced, b"-aB-cbc");
+        assert_eq!(iterated, b"-aB-cbc");
+
+        let replacements = vec![Replacement::new(b"b", b"B"), Replacement::new(b"", b"-").with_limit(1)];
+        let iterated: Vec<u8> = ReplaceBuilder::new(replacements).allow_empty_patterns()
+            .apply(b"abcb".iter().cloned())
+            .collect();
+        assert_eq!(iterated, b"-aBcB");
+    }
+}